@@ -0,0 +1,456 @@
+use std::future::Future;
+use std::io;
+use std::io::ErrorKind;
+use std::os::fd::OwnedFd;
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::{Arc, OnceLock};
+use std::time::SystemTime;
+
+use crossbeam_channel::{bounded, select, Receiver, Sender};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::process::{Child, ChildStdin, ChildStdout};
+use tokio::sync::{oneshot, Notify};
+use tokio::task::JoinSet;
+use tokio::time::{sleep_until, Instant};
+use tracing::warn;
+
+use crate::debug::{CommandDebug, Redaction};
+use crate::impls::merge_output;
+use crate::pipeline::pipeline_status;
+use crate::sink::{check_tee, tee_failure, Captured, KillHook, OutputSink, StreamOptions, TeeFailure};
+use crate::{Cmd, CmdOutput, Pipeline, PipelineOutput, TermPolicy, Termination};
+
+/// Handle to a command spawned with [`Cmd::spawn_async`].
+///
+/// The timeout and the cancel signal of the [`Cmd`] are enforced while waiting for the command.
+/// The child is killed if the handle is dropped before the command has completed.
+#[derive(Debug)]
+pub struct AsyncCmdHandle {
+	child: Child,
+	pid: u32,
+	command_line: String,
+	started_at: SystemTime,
+	start: Instant,
+	// set when the command is terminated by this crate
+	cause: Arc<OnceLock<Termination>>,
+	kill_tree: bool,
+	termination: TermPolicy,
+	debug: bool,
+	deadline: Option<Instant>,
+	cancel: Option<oneshot::Receiver<()>>,
+	stdout_options: StreamOptions,
+	stderr_options: StreamOptions,
+	// notified when the output exceeds its limit with `Overflow::Kill`, or its tee fails with `kill_on_tee_error`
+	kill_request: Arc<Notify>,
+	// notified when the pipeline this command belongs to is terminated with the given policy
+	stop: Option<(Arc<Notify>, TermPolicy)>,
+	// dropping it stops the thread forwarding the cancel signal
+	_cancel_guard: Option<Sender<()>>,
+}
+
+impl Cmd {
+	/// Spawns the command on the tokio runtime.
+	pub fn spawn_async(mut self) -> crate::Result<AsyncCmdHandle> {
+		if self.debug {
+			self.debug();
+		}
+
+		let command_line = self.as_string();
+		let redaction = std::mem::take(&mut self.redaction);
+		let merge_stderr = self.merge_stderr;
+		let (stdout_options, stderr_options) = self.take_stream_options();
+		let (cancel, cancel_guard) = forward_cancel(self.signal.take())?.unzip();
+		let deadline = self.timeout.take().map(|t| Instant::now() + t);
+		let (kill_tree, termination, debug) = (self.kill_tree, self.termination, self.debug);
+
+		let started_at = SystemTime::now();
+		let start = Instant::now();
+		let child = spawn_async(self.command(), &redaction, merge_stderr)?;
+		Ok(AsyncCmdHandle {
+			pid: child.id().unwrap_or_default(),
+			child,
+			command_line,
+			started_at,
+			start,
+			cause: Default::default(),
+			kill_tree,
+			termination,
+			debug,
+			deadline,
+			cancel,
+			stdout_options,
+			stderr_options,
+			kill_request: Arc::new(Notify::new()),
+			stop: None,
+			_cancel_guard: cancel_guard,
+		})
+	}
+
+	pub async fn output_async(self) -> crate::Result<CmdOutput> {
+		let error_on_termination = self.error_on_termination;
+		let output = self.spawn_async()?.wait_with_output().await?;
+
+		if error_on_termination {
+			output.check_termination()
+		} else {
+			Ok(output)
+		}
+	}
+
+	/// Pipes the stdout of this command into `cmd2` like [`Cmd::pipe`], then runs the pipeline
+	/// with [`Pipeline::output_async`].
+	pub async fn pipe_async<T>(self, cmd2: T) -> crate::Result<PipelineOutput>
+	where
+		T: Into<Cmd>,
+	{
+		self.pipe(cmd2).output_async().await
+	}
+}
+
+impl Pipeline {
+	/// Spawns all the stages on the tokio runtime and waits for all of them to exit.
+	/// See [`Pipeline::output`].
+	pub async fn output_async(mut self) -> crate::Result<PipelineOutput> {
+		if self.stages.is_empty() {
+			return Err(io::Error::new(ErrorKind::InvalidInput, "empty pipeline").into());
+		}
+
+		if self.debug {
+			self.debug();
+		}
+
+		let (mut cancel, _cancel_guard) = forward_cancel(self.signal.take())?.unzip();
+		let deadline = self.timeout.take().map(|t| Instant::now() + t);
+
+		let count = self.stages.len();
+		// the stages are killed when the set is dropped, if spawning one of them fails
+		let mut tasks = JoinSet::new();
+		let mut stops = Vec::with_capacity(count);
+		let mut previous_stdout: Option<Stdio> = None;
+
+		for (index, mut stage) in self.stages.into_iter().enumerate() {
+			if let Some(stdout) = previous_stdout.take() {
+				stage.stdin = Some(stdout);
+			}
+
+			let is_last = index + 1 == count;
+			if !is_last {
+				stage.stdout = Some(Stdio::piped());
+			}
+
+			let mut handle = stage.spawn_async()?;
+			if !is_last {
+				previous_stdout = handle.child.stdout.take().map(TryInto::try_into).transpose()?;
+			}
+
+			let stop = Arc::new(Notify::new());
+			handle.stop = Some((Arc::clone(&stop), self.termination));
+			stops.push(stop);
+			tasks.spawn(async move { (index, handle.collect_output().await) });
+		}
+
+		let mut outputs: Vec<Option<CmdOutput>> = vec![None; count];
+		let mut tee_failures = Vec::new();
+		let mut stopped = false;
+		loop {
+			tokio::select! {
+				joined = tasks.join_next() => match joined {
+					Some(joined) => {
+						let (index, output) = joined.map_err(io::Error::other)?;
+						let (output, tee_failure) = output?;
+						outputs[index] = Some(output);
+						tee_failures.extend(tee_failure.map(|failure| (index, failure)));
+					}
+					None => break,
+				},
+
+				_ = stop_requested(deadline, &mut cancel), if !stopped => {
+					if self.debug {
+						warn!("pipeline timeout or ctrl+c! terminating the stages...");
+					}
+					// the last stages first, so that the previous ones aren't killed by a broken pipe
+					for stop in stops.iter().rev() {
+						stop.notify_one();
+					}
+					stopped = true;
+				}
+			}
+		}
+
+		let outputs = outputs.into_iter().flatten().collect::<Vec<_>>();
+		let statuses = outputs.iter().map(|o| o.status).collect::<Vec<_>>();
+		let mut stage_stderr = Vec::with_capacity(count);
+		let mut stdout = Vec::new();
+		for output in outputs {
+			stage_stderr.push(output.stderr);
+			stdout = output.stdout;
+		}
+
+		// the first stage whose tee failed, like the synchronous pipeline
+		tee_failures.sort_by_key(|(index, (stream, _))| (*stream == "stderr", *index));
+		let output = PipelineOutput {
+			status: pipeline_status(&statuses, self.pipefail),
+			stderr: stage_stderr[count - 1].clone(),
+			statuses,
+			stdout,
+			stage_stderr,
+		};
+		check_tee(output, tee_failures.into_iter().next().map(|(_, failure)| failure))
+	}
+}
+
+impl AsyncCmdHandle {
+	/// Returns the pid of the command, or `None` if it has already been reaped.
+	pub fn id(&self) -> Option<u32> {
+		self.child.id()
+	}
+
+	pub fn stdin(&mut self) -> Option<&mut ChildStdin> {
+		self.child.stdin.as_mut()
+	}
+
+	pub fn take_stdin(&mut self) -> Option<ChildStdin> {
+		self.child.stdin.take()
+	}
+
+	/// Sends `signal` to the command (or to its process group when `kill_tree` is set). Does nothing if the command
+	/// has already been reaped, or with `kill_tree` if the group has no members left.
+	pub fn send_signal(&self, signal: i32) -> io::Result<()> {
+		let reaped = self.child.id().is_none();
+		if reaped && !self.kill_tree {
+			return Ok(());
+		}
+
+		let pid = self.pid as libc::pid_t;
+		let pid = if self.kill_tree { -pid } else { pid };
+		if unsafe { libc::kill(pid, signal) } == 0 {
+			return Ok(());
+		}
+
+		match io::Error::last_os_error() {
+			err if reaped && err.raw_os_error() == Some(libc::ESRCH) => Ok(()),
+			err => Err(err),
+		}
+	}
+
+	pub fn kill(&self) -> io::Result<()> {
+		self.send_signal(signal_hook::consts::SIGKILL)
+	}
+
+	/// Waits for the command to exit, terminating it when the timeout expires or the cancel signal is received.
+	pub async fn wait(&mut self) -> crate::Result<ExitStatus> {
+		let (status, ()) = self.supervise(async {}).await;
+		Ok(status?)
+	}
+
+	/// Waits for the command to exit and for `output` to complete, terminating the command when the timeout
+	/// expires or the cancel signal is received. With `kill_tree`, the process group is still terminated after
+	/// the command has exited, since the processes left in it may keep its stdout or stderr open.
+	async fn supervise<F: Future>(&mut self, output: F) -> (io::Result<ExitStatus>, F::Output) {
+		let mut escalate: Option<(Instant, i32)> = None;
+		let mut terminated = false;
+		let mut status = None;
+		let mut result = None;
+		tokio::pin!(output);
+
+		loop {
+			if let (Some(_), Some(_)) = (&status, &result) {
+				return (status.take().unwrap(), result.take().unwrap());
+			}
+
+			let armed = !terminated && (status.is_none() || self.kill_tree);
+			let deadline = self.deadline;
+			let stop = self.stop.as_ref().map(|(stop, _)| Arc::clone(stop));
+			tokio::select! {
+				biased;
+
+				exited = self.child.wait(), if status.is_none() => status = Some(exited),
+
+				done = &mut output, if result.is_none() => result = Some(done),
+
+				_ = sleep_until_opt(deadline), if armed => {
+					if self.debug {
+						warn!("command timeout! terminating the process...");
+					}
+					let _ = self.cause.set(Termination::TimedOut);
+					escalate = self.terminate(self.termination);
+					terminated = true;
+				}
+
+				_ = recv_opt(&mut self.cancel), if armed => {
+					self.cancel = None;
+					if self.debug {
+						warn!("ctrl+c received");
+					}
+					let _ = self.cause.set(Termination::Cancelled);
+					escalate = self.terminate(self.termination);
+					terminated = true;
+				}
+
+				_ = notified_opt(stop), if armed => {
+					if let Some((_, policy)) = self.stop {
+						escalate = self.terminate(policy);
+					}
+					terminated = true;
+				}
+
+				_ = self.kill_request.notified(), if armed => {
+					if self.debug {
+						warn!("output limit exceeded or tee failed! killing the process...");
+					}
+					let _ = self.kill();
+					terminated = true;
+				}
+
+				_ = sleep_until_opt(escalate.map(|(at, _)| at)), if escalate.is_some() => {
+					if let Some((_, then)) = escalate.take() {
+						if self.debug {
+							warn!("grace period expired, sending signal {then}...");
+						}
+						let _ = self.send_signal(then);
+					}
+				}
+			}
+		}
+	}
+
+	/// Waits for the command to exit, collecting its stdout and stderr.
+	pub async fn wait_with_output(self) -> crate::Result<CmdOutput> {
+		let (output, tee_failure) = self.collect_output().await?;
+		check_tee(output, tee_failure)
+	}
+
+	/// Waits for the command to exit, collecting its stdout and stderr, and the first error of their tees.
+	async fn collect_output(mut self) -> crate::Result<(CmdOutput, Option<TeeFailure>)> {
+		let stdout = self.child.stdout.take();
+		let stderr = self.child.stderr.take();
+		drop(self.take_stdin());
+
+		let mut stdout_options = std::mem::take(&mut self.stdout_options);
+		let mut stderr_options = std::mem::take(&mut self.stderr_options);
+		let kill_request = Arc::clone(&self.kill_request);
+		let cause = Arc::clone(&self.cause);
+		let kill: KillHook = Arc::new(move |termination| {
+			let _ = cause.set(termination);
+			kill_request.notify_one();
+		});
+		stdout_options.kill = Some(Arc::clone(&kill));
+		stderr_options.kill = Some(kill);
+
+		let stdout_sink = OutputSink::new(stdout_options);
+		let stderr_sink = OutputSink::new(stderr_options);
+
+		let readers = async { tokio::join!(read_to_end(stdout, stdout_sink), read_to_end(stderr, stderr_sink)) };
+		let (status, (stdout, stderr)) = self.supervise(readers).await;
+		let elapsed = self.start.elapsed();
+		let status = status?;
+		let (mut stdout, mut stderr) = (stdout?, stderr?);
+		let tee_failure = tee_failure(&mut stdout, &mut stderr);
+		let output = CmdOutput {
+			status,
+			stdout: stdout.bytes,
+			stderr: stderr.bytes,
+			stdout_truncated: stdout.truncated,
+			stderr_truncated: stderr.truncated,
+			pid: self.pid,
+			started_at: self.started_at,
+			elapsed,
+			command_line: std::mem::take(&mut self.command_line),
+			termination: Termination::from_status(&status, self.cause.get().copied()),
+			attempts: 1,
+		};
+		Ok((output, tee_failure))
+	}
+
+	/// Sends the first signal of `policy`, and returns when and which signal must follow it.
+	fn terminate(&self, policy: TermPolicy) -> Option<(Instant, i32)> {
+		let _ = self.send_signal(policy.signal);
+		if policy.signal == policy.then {
+			None
+		} else {
+			Some((Instant::now() + policy.grace, policy.then))
+		}
+	}
+}
+
+/// Forwards the cancel signal to a tokio channel, from a thread which stops when the guard is dropped.
+fn forward_cancel(signal: Option<Receiver<()>>) -> io::Result<Option<(oneshot::Receiver<()>, Sender<()>)>> {
+	let Some(signal) = signal else {
+		return Ok(None);
+	};
+
+	let (sender, receiver) = oneshot::channel();
+	let (guard, done) = bounded::<()>(0);
+	std::thread::Builder::new().name("cmd_cancel".to_string()).spawn(move || {
+		select! {
+			recv(signal) -> msg => if msg.is_ok() {
+				let _ = sender.send(());
+			},
+			recv(done) -> _ => {},
+		}
+	})?;
+	Ok(Some((receiver, guard)))
+}
+
+fn spawn_async(mut command: Command, redaction: &Redaction, merge_stderr: bool) -> crate::Result<Child> {
+	let merged = if merge_stderr { Some(merge_output(&mut command)?) } else { None };
+	let mut async_command = tokio::process::Command::from(command);
+	async_command.kill_on_drop(true);
+	let mut child = match async_command.spawn() {
+		Ok(child) => child,
+		Err(err) => return Err(crate::Error::spawn(async_command.as_std(), redaction, err)),
+	};
+	// the writing ends of the pipe must be closed in the parent
+	drop(async_command);
+	if let Some(reader) = merged {
+		let stdout = std::process::ChildStdout::from(OwnedFd::from(reader));
+		child.stdout = Some(ChildStdout::from_std(stdout)?);
+	}
+	Ok(child)
+}
+
+async fn read_to_end<R: AsyncRead + Unpin>(stream: Option<R>, mut sink: OutputSink) -> io::Result<Captured> {
+	if let Some(mut stream) = stream {
+		let mut buffer = [0u8; 8192];
+		loop {
+			match stream.read(&mut buffer).await? {
+				0 => break,
+				n => sink.push(&buffer[..n]),
+			}
+		}
+	}
+	Ok(sink.finish())
+}
+
+async fn sleep_until_opt(deadline: Option<Instant>) {
+	match deadline {
+		Some(deadline) => sleep_until(deadline).await,
+		None => std::future::pending().await,
+	}
+}
+
+async fn notified_opt(notify: Option<Arc<Notify>>) {
+	match notify {
+		Some(notify) => notify.notified().await,
+		None => std::future::pending().await,
+	}
+}
+
+/// Completes when the deadline expires or the cancel signal is received.
+async fn stop_requested(deadline: Option<Instant>, cancel: &mut Option<oneshot::Receiver<()>>) {
+	tokio::select! {
+		_ = sleep_until_opt(deadline) => {}
+		_ = recv_opt(cancel) => {}
+	}
+}
+
+async fn recv_opt(receiver: &mut Option<oneshot::Receiver<()>>) {
+	match receiver {
+		Some(receiver) => {
+			if receiver.await.is_err() {
+				std::future::pending::<()>().await;
+			}
+		}
+		None => std::future::pending().await,
+	}
+}
@@ -1,13 +1,95 @@
-use std::ffi::OsStr;
-use std::ffi::OsString;
-use std::path::Path;
+use std::ffi::{OsStr, OsString};
+use std::fmt::{Debug, Formatter};
 
 use tracing::trace;
 
-use crate::Cmd;
+use crate::shell;
+use crate::{Cmd, CommandBuilder, Pipeline};
+
+/// What is rendered in place of a secret value. It's quoted like any other word in a command line,
+/// so it can't be told apart from an argument which is really `****`, but a shell won't expand it.
+pub(crate) const REDACTED: &str = "****";
+
+/// The arguments and the environment variables whose values are hidden when a command is rendered.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Redaction {
+	/// The indexes of the secret arguments.
+	pub(crate) args: Vec<usize>,
+	/// The keys of the secret environment variables.
+	pub(crate) envs: Vec<OsString>,
+}
+
+impl Redaction {
+	pub(crate) fn args<'a>(&'a self, args: &'a [OsString]) -> impl Iterator<Item = &'a OsStr> {
+		args.iter().enumerate().map(|(index, arg)| match self.args.contains(&index) {
+			true => OsStr::new(REDACTED),
+			false => arg.as_os_str(),
+		})
+	}
+
+	pub(crate) fn env<'a>(&self, key: &OsStr, value: Option<&'a OsStr>) -> Option<&'a OsStr> {
+		match value {
+			Some(_) if self.envs.iter().any(|k| k == key) => Some(OsStr::new(REDACTED)),
+			value => value,
+		}
+	}
+}
+
+/// Implements `Debug` for the structs holding the configuration of a command, with the values of
+/// their secret arguments and environment variables redacted.
+macro_rules! debug_redacted {
+	($type:ident) => {
+		impl Debug for $type {
+			fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+				let args = self.redaction.args(&self.args).collect::<Vec<_>>();
+				let envs = self
+					.envs
+					.iter()
+					.map(|(k, v)| (k, self.redaction.env(k, v.as_deref())))
+					.collect::<Vec<_>>();
+				f.debug_struct(stringify!($type))
+					.field("debug", &self.debug)
+					.field("program", &self.program)
+					.field("args", &args)
+					.field("cwd", &self.cwd)
+					.field("envs", &envs)
+					.field("env_clear", &self.env_clear)
+					.field("env_inherit", &self.env_inherit)
+					.field("stdin", &self.stdin)
+					.field("stdout", &self.stdout)
+					.field("stderr", &self.stderr)
+					.field("fd_stdio", &self.fd_stdio)
+					.field("timeout", &self.timeout)
+					.field("signal", &self.signal)
+					.field("termination", &self.termination)
+					.field("process_group", &self.process_group)
+					.field("kill_tree", &self.kill_tree)
+					.field("kill_on_drop", &self.kill_on_drop)
+					.field("error_on_termination", &self.error_on_termination)
+					.field("merge_stderr", &self.merge_stderr)
+					.field("stdin_data", &self.stdin_data)
+					.field("retry", &self.retry)
+					.field("success", &self.success)
+					.field("stdout_options", &self.stdout_options)
+					.field("stderr_options", &self.stderr_options)
+					.field("redaction", &self.redaction)
+					.field("trace_target", &self.trace_target)
+					.field("trace_level", &self.trace_level)
+					.field("trace_label", &self.trace_label)
+					.finish()
+			}
+		}
+	};
+}
+
+debug_redacted!(Cmd);
+debug_redacted!(CommandBuilder);
 
 pub trait CommandDebug {
 	fn debug(&mut self) -> &mut Self;
+
+	/// Renders the command as a line which can be pasted into bash, with its working directory
+	/// and its environment changes, e.g. `cd dir && FOO=bar prog 'an arg'`.
 	fn as_string(&self) -> String;
 }
 
@@ -18,15 +100,13 @@ impl CommandDebug for std::process::Command {
 	}
 
 	fn as_string(&self) -> String {
-		let path = Path::new(self.get_program());
-		let s = self.get_args().fold(vec![], |mut a: Vec<&OsStr>, b: &OsStr| {
-			a.push(b);
-			a
-		});
-		format!(
-			"{} {}",
-			path.file_name().unwrap().to_str().unwrap(),
-			s.join(OsString::from(" ").as_os_str()).to_str().unwrap().trim()
+		shell::command_line(
+			self.get_program(),
+			self.get_args(),
+			self.get_current_dir().map(|p| p.as_os_str()),
+			self.get_envs(),
+			false,
+			None,
 		)
 	}
 }
@@ -38,17 +118,32 @@ impl CommandDebug for Cmd {
 	}
 
 	fn as_string(&self) -> String {
-		let path = Path::new(self.program.as_os_str());
-		let s = (&self.args)
-			.into_iter()
-			.fold(Vec::new(), |mut a: Vec<OsString>, b: &OsString| {
-				a.push(b.clone());
-				a
-			});
-		format!(
-			"{} {}",
-			path.file_name().unwrap().to_str().unwrap(),
-			s.join(OsString::from(" ").as_os_str()).to_str().unwrap().trim()
+		shell::command_line(
+			&self.program,
+			self.redaction.args(&self.args),
+			self.cwd.as_deref(),
+			self.envs.iter().map(|(k, v)| (k.as_os_str(), self.redaction.env(k, v.as_deref()))),
+			self.env_clear,
+			self.env_inherit.as_deref(),
 		)
 	}
 }
+
+impl CommandDebug for Pipeline {
+	fn debug(&mut self) -> &mut Self {
+		trace!("Executing `{}`...", self.as_string());
+		self
+	}
+
+	fn as_string(&self) -> String {
+		self.stages
+			.iter()
+			.map(|s| match s.cwd {
+				// `cd` must not change the directory of the other stages
+				Some(_) => format!("({})", s.as_string()),
+				None => s.as_string(),
+			})
+			.collect::<Vec<_>>()
+			.join(" | ")
+	}
+}
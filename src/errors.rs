@@ -1,8 +1,11 @@
+use std::ffi::OsStr;
 use std::fmt::{Debug, Display, Formatter};
-use std::process::{ExitStatus, Output};
+use std::io::ErrorKind;
+use std::process::{Command, ExitStatus, Output};
 
 use thiserror::Error;
 
+use crate::debug::{CommandDebug, Redaction};
 use crate::Vec8ToString;
 
 #[derive(Error, Clone, PartialEq, Eq)]
@@ -10,6 +13,14 @@ pub struct CmdError {
 	pub status: Option<ExitStatus>,
 	pub stdout: Vec<u8>,
 	pub stderr: Vec<u8>,
+	/// The command line which failed, with the secret values redacted.
+	pub command: Option<String>,
+	/// How many times the command was run before giving up.
+	pub attempts: u32,
+	/// Part of the stdout was dropped because it exceeded the limit.
+	pub stdout_truncated: bool,
+	/// Part of the stderr was dropped because it exceeded the limit.
+	pub stderr_truncated: bool,
 }
 
 impl Debug for CmdError {
@@ -18,6 +29,10 @@ impl Debug for CmdError {
 			.field("status", &self.status)
 			.field("stdout", &self.stdout.as_str())
 			.field("stderr", &self.stderr.as_str())
+			.field("command", &self.command)
+			.field("attempts", &self.attempts)
+			.field("stdout_truncated", &self.stdout_truncated)
+			.field("stderr_truncated", &self.stderr_truncated)
 			.finish()
 	}
 }
@@ -34,12 +49,58 @@ impl From<Output> for crate::Error {
 	}
 }
 
+impl crate::Error {
+	/// The secret arguments are replaced by `****`.
+	pub(crate) fn spawn(command: &Command, redaction: &Redaction, source: std::io::Error) -> Self {
+		let args = command.get_args().map(OsStr::to_owned).collect::<Vec<_>>();
+		crate::Error::Spawn {
+			program: command.get_program().to_owned(),
+			args: redaction.args(&args).map(OsStr::to_owned).collect(),
+			cwd: command.get_current_dir().map(|p| p.as_os_str().to_owned()),
+			source,
+		}
+	}
+
+	/// Returns the [`ErrorKind`] of the error which prevented the command from being spawned.
+	pub fn spawn_error_kind(&self) -> Option<ErrorKind> {
+		match self {
+			crate::Error::Spawn { source, .. } => Some(source.kind()),
+			_ => None,
+		}
+	}
+
+	pub fn is_spawn(&self) -> bool {
+		matches!(self, crate::Error::Spawn { .. })
+	}
+
+	/// The program (or the working directory) could not be found.
+	pub fn is_not_found(&self) -> bool {
+		self.spawn_error_kind() == Some(ErrorKind::NotFound)
+	}
+
+	pub fn is_permission_denied(&self) -> bool {
+		self.spawn_error_kind() == Some(ErrorKind::PermissionDenied)
+	}
+
+	pub fn is_timeout(&self) -> bool {
+		matches!(self, crate::Error::Timeout { .. })
+	}
+
+	pub fn is_cancelled(&self) -> bool {
+		matches!(self, crate::Error::Cancelled { .. })
+	}
+}
+
 impl CmdError {
 	pub fn from_err(status: ExitStatus, stdout: Vec<u8>, stderr: Vec<u8>) -> Self {
 		CmdError {
 			status: Some(status),
 			stdout,
 			stderr,
+			command: None,
+			attempts: 1,
+			stdout_truncated: false,
+			stderr_truncated: false,
 		}
 	}
 
@@ -48,17 +109,32 @@ impl CmdError {
 			status: Some(status),
 			stdout: vec![],
 			stderr: vec![],
+			command: None,
+			attempts: 1,
+			stdout_truncated: false,
+			stderr_truncated: false,
 		}
 	}
 
+	#[allow(clippy::should_implement_trait)]
 	pub fn from_str(msg: &str) -> Self {
 		CmdError {
 			status: None,
 			stdout: vec![],
 			stderr: msg.to_owned().into_bytes(),
+			command: None,
+			attempts: 1,
+			stdout_truncated: false,
+			stderr_truncated: false,
 		}
 	}
 
+	/// Records the command which failed, rendered with [`CommandDebug::as_string`].
+	pub fn with_command<C: CommandDebug>(mut self, command: &C) -> Self {
+		self.command = Some(command.as_string());
+		self
+	}
+
 	pub fn exit_code(&self) -> Option<i32> {
 		match self.status {
 			Some(s) => s.code(),
@@ -69,14 +145,22 @@ impl CmdError {
 
 impl Display for CmdError {
 	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		if let Some(command) = &self.command {
+			let _ = write!(f, "`{}` failed, ", command);
+		}
+
+		if self.attempts > 1 {
+			let _ = write!(f, "after {} attempts, ", self.attempts);
+		}
+
 		if let Some(status) = self.status {
 			if let Some(code) = status.code() {
 				let _ = write!(f, "exit code: {}", code);
 			} else {
-				let _ = write!(f, "exit status: {}", self.status.unwrap_or(ExitStatus::default()));
+				let _ = write!(f, "exit status: {}", self.status.unwrap_or_default());
 			}
 		} else {
-			let _ = write!(f, "exit status: {}", self.status.unwrap_or(ExitStatus::default()));
+			let _ = write!(f, "exit status: {}", self.status.unwrap_or_default());
 		}
 
 		if !self.stderr.is_empty() {
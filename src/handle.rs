@@ -0,0 +1,188 @@
+use std::io;
+use std::process::{ChildStderr, ChildStdin, ChildStdout, ExitStatus};
+use std::sync::{Arc, OnceLock};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
+
+use tracing::warn;
+
+use crate::debug::CommandDebug;
+use crate::impls::{read_to_end, spawn};
+use crate::sink::{check_tee, kill_hook, tee_failure, StreamOptions};
+use crate::waiter::{or_kill_all, ChildWaiter};
+use crate::{Cmd, CmdOutput, Termination};
+
+/// Handle to a command spawned with [`Cmd::spawn`].
+///
+/// The timeout and the cancel signal of the [`Cmd`] are enforced in the background.
+/// Unless [`crate::CommandBuilder::kill_on_drop`] is set to `false`, the command is killed
+/// when the handle is dropped before it has exited.
+#[derive(Debug)]
+pub struct CmdHandle {
+	waiter: Arc<ChildWaiter>,
+	stdin: Option<ChildStdin>,
+	stdout: Option<ChildStdout>,
+	stderr: Option<ChildStderr>,
+	stdout_options: StreamOptions,
+	stderr_options: StreamOptions,
+	supervisor_thread: Option<JoinHandle<()>>,
+	kill_on_drop: bool,
+	command_line: String,
+	started_at: SystemTime,
+	start: Instant,
+	// set when the command is terminated by this crate
+	cause: Arc<OnceLock<Termination>>,
+}
+
+impl Cmd {
+	/// Spawns the command without waiting for it.
+	pub fn spawn(mut self) -> crate::Result<CmdHandle> {
+		if self.debug {
+			self.debug();
+		}
+
+		let command_line = self.as_string();
+		let supervisor = self.supervisor();
+		let cause = Arc::clone(&supervisor.cause);
+		let kill_tree = self.kill_tree;
+		let kill_on_drop = self.kill_on_drop;
+		let (stdout_options, stderr_options) = self.take_stream_options();
+
+		let redaction = std::mem::take(&mut self.redaction);
+		let merge_stderr = self.merge_stderr;
+		let mut command = self.command();
+		let started_at = SystemTime::now();
+		let start = Instant::now();
+		let mut child = spawn(&mut command, &redaction, merge_stderr)?;
+		drop(command);
+
+		let stdin = child.stdin.take();
+		let stdout = child.stdout.take();
+		let stderr = child.stderr.take();
+
+		let waiter = Arc::new(ChildWaiter::new(child, kill_tree)?);
+		let waiters = std::slice::from_ref(&waiter);
+		let supervisor_thread = or_kill_all(supervisor.spawn(vec![Arc::clone(&waiter)]), waiters)?;
+
+		Ok(CmdHandle {
+			waiter,
+			stdin,
+			stdout,
+			stderr,
+			stdout_options,
+			stderr_options,
+			supervisor_thread,
+			kill_on_drop,
+			command_line,
+			started_at,
+			start,
+			cause,
+		})
+	}
+}
+
+impl CmdHandle {
+	pub fn pid(&self) -> u32 {
+		self.waiter.id()
+	}
+
+	/// The stdin of the command, if it was configured with `Stdio::piped()`.
+	pub fn stdin(&mut self) -> Option<&mut ChildStdin> {
+		self.stdin.as_mut()
+	}
+
+	/// Takes the stdin of the command. Dropping it closes the pipe.
+	pub fn take_stdin(&mut self) -> Option<ChildStdin> {
+		self.stdin.take()
+	}
+
+	pub fn take_stdout(&mut self) -> Option<ChildStdout> {
+		self.stdout.take()
+	}
+
+	pub fn take_stderr(&mut self) -> Option<ChildStderr> {
+		self.stderr.take()
+	}
+
+	/// Waits for the command to exit. The stdin is closed before waiting.
+	///
+	/// The output of the command is not read: a command writing more than the pipe buffer
+	/// to stdout or stderr will block, use [`CmdHandle::wait_with_output`] instead.
+	pub fn wait(&mut self) -> crate::Result<ExitStatus> {
+		drop(self.stdin.take());
+		let status = self.waiter.wait()?;
+		self.waiter.release();
+		self.join_supervisor();
+		Ok(status)
+	}
+
+	/// Waits for the command to exit for at most `timeout`. Returns `None` if it's still running.
+	pub fn wait_timeout(&mut self, timeout: Duration) -> crate::Result<Option<ExitStatus>> {
+		Ok(self.waiter.wait_timeout(timeout)?)
+	}
+
+	pub fn try_wait(&mut self) -> crate::Result<Option<ExitStatus>> {
+		Ok(self.waiter.try_wait()?)
+	}
+
+	pub fn kill(&self) -> io::Result<()> {
+		self.send_signal(signal_hook::consts::SIGKILL)
+	}
+
+	/// Sends `signal` to the command, or to its process group when `kill_tree` is set.
+	/// Does nothing if the command has already exited, or with `kill_tree` if the group has no members left.
+	pub fn send_signal(&self, signal: i32) -> io::Result<()> {
+		self.waiter.signal(signal)
+	}
+
+	/// Closes the stdin, collects stdout and stderr and waits for the command to exit.
+	pub fn wait_with_output(mut self) -> crate::Result<CmdOutput> {
+		drop(self.stdin.take());
+
+		let mut stdout_options = std::mem::take(&mut self.stdout_options);
+		let mut stderr_options = std::mem::take(&mut self.stderr_options);
+		let kill = kill_hook(&self.waiter, Some(Arc::clone(&self.cause)));
+		stdout_options.kill = Some(Arc::clone(&kill));
+		stderr_options.kill = Some(kill);
+
+		let output = read_to_end(self.stdout.take(), self.stderr.take(), stdout_options, stderr_options);
+		self.waiter.release();
+		let status = self.waiter.wait();
+		let elapsed = self.start.elapsed();
+		self.join_supervisor();
+
+		let (mut stdout, mut stderr) = output?;
+		let tee_failure = tee_failure(&mut stdout, &mut stderr);
+		let status = status?;
+		let output = CmdOutput {
+			status,
+			stdout: stdout.bytes,
+			stderr: stderr.bytes,
+			stdout_truncated: stdout.truncated,
+			stderr_truncated: stderr.truncated,
+			pid: self.waiter.id(),
+			started_at: self.started_at,
+			elapsed,
+			command_line: std::mem::take(&mut self.command_line),
+			termination: Termination::from_status(&status, self.cause.get().copied()),
+			attempts: 1,
+		};
+		check_tee(output, tee_failure)
+	}
+
+	fn join_supervisor(&mut self) {
+		if let Some(Err(_err)) = self.supervisor_thread.take().map(|t| t.join()) {
+			warn!("failed to join the thread!");
+		}
+	}
+}
+
+impl Drop for CmdHandle {
+	fn drop(&mut self) {
+		// once the command has exited, not even the rest of its process group is killed
+		if self.kill_on_drop && matches!(self.waiter.try_wait(), Ok(None)) {
+			let _ = self.kill();
+		}
+		self.waiter.release();
+	}
+}
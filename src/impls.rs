@@ -1,60 +1,60 @@
+use std::any::TypeId;
+use std::borrow::Cow;
 use std::ffi::{OsStr, OsString};
 use std::fmt::{Display, Formatter};
+use std::fs::{File, OpenOptions};
 use std::io;
-use std::io::{BufRead, BufReader, ErrorKind};
+use std::io::{BufRead, PipeReader, Write};
+use std::os::fd::OwnedFd;
+use std::os::unix::process::CommandExt;
 use std::path::Path;
-use std::process::{ChildStderr, ChildStdout, Command, ExitStatus, Output, Stdio};
-use std::sync::{Arc, Condvar, Mutex};
-use std::time::Duration;
+use std::process::{Child, ChildStderr, ChildStdin, ChildStdout, Command, ExitStatus, Stdio};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
 
 use crossbeam::channel::Receiver;
-use crossbeam_channel::{tick, Select};
-use tracing::{error, trace, warn};
-
-use crate::debug::CommandDebug;
-use crate::errors::CmdError;
-use crate::{Cmd, CommandBuilder, Error, OutputResult, Vec8ToString};
-
+use crossbeam_channel::{after, never};
+use tracing::{warn, Level, Span};
+
+use crate::debug::{CommandDebug, Redaction};
+use crate::shell;
+use crate::sink;
+use crate::sink::{check_tee, kill_hook, tee_failure, Captured, OutputSink, Overflow, StreamOptions, MAX_LINE_BYTES};
+use crate::span::CmdSpan;
+use crate::text;
+use crate::waiter::{or_kill_all, ChildWaiter, Supervisor};
+use crate::{
+	Cmd, CmdOutput, CommandBuilder, Error, Pipeline, RetryPolicy, SuccessPolicy, TermPolicy, Termination, TraceTarget,
+	Vec8ToString,
+};
+
+/// Renders the program and its arguments, quoted as needed. See [`CommandDebug::as_string`]
+/// for a rendering including the working directory and the environment.
 impl Display for Cmd {
 	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-		write!(f, "{:?} {:?}", self.program, self.args)
+		write_words(f, &self.program, &self.args, &self.redaction)
 	}
 }
 
+/// Renders the program and its arguments, quoted so that [`CommandBuilder::parse`] reads them back.
 impl Display for CommandBuilder {
 	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-		write!(
-			f,
-			"{:} {:}",
-			self.program.to_str().unwrap(),
-			self.args.join(OsStr::new(" ")).to_str().unwrap()
-		)
+		write_words(f, &self.program, &self.args, &self.redaction)
 	}
 }
 
-impl OutputResult for Output {
-	fn to_result(&self) -> crate::Result<Vec<u8>> {
-		if self.status.success() && self.stderr.is_empty() {
-			Ok(self.stdout.to_owned())
-		} else {
-			Err(crate::Error::CommandError(CmdError::from_err(
-				self.status,
-				self.stdout.to_owned(),
-				self.stderr.to_owned(),
-			)))
-		}
-	}
+fn write_words(f: &mut Formatter<'_>, program: &OsStr, args: &[OsString], redaction: &Redaction) -> std::fmt::Result {
+	let words = std::iter::once(program).chain(redaction.args(args));
+	write!(f, "{}", shell::join(words))
+}
 
-	fn try_to_result(&self) -> crate::Result<Vec<u8>> {
-		if self.status.code().is_none() && self.stderr.is_empty() {
-			Ok(self.stdout.to_owned())
-		} else {
-			Err(crate::Error::CommandError(CmdError::from_err(
-				self.status,
-				self.stdout.to_owned(),
-				self.stderr.to_owned(),
-			)))
-		}
+impl FromStr for CommandBuilder {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		CommandBuilder::parse(s)
 	}
 }
 
@@ -66,11 +66,46 @@ impl CommandBuilder {
 			cwd: None,
 			debug: false,
 			args: vec![],
+			envs: vec![],
+			env_clear: false,
+			env_inherit: None,
 			stdin: None,
 			stdout: Some(Stdio::piped()),
 			stderr: Some(Stdio::piped()),
+			fd_stdio: false,
 			signal: None,
-		}
+			termination: TermPolicy::kill(),
+			process_group: false,
+			kill_tree: false,
+			kill_on_drop: true,
+			error_on_termination: false,
+			merge_stderr: false,
+			stdin_data: None,
+			retry: None,
+			success: SuccessPolicy::default(),
+			stdout_options: StreamOptions::default(),
+			stderr_options: StreamOptions::default(),
+			redaction: Redaction::default(),
+			trace_target: TraceTarget::Default,
+			trace_level: Level::DEBUG,
+			trace_label: None,
+		}
+	}
+
+	/// Parses a command line, splitting it into the program and its arguments with the POSIX shell
+	/// quoting rules. No shell is involved: variables, globs and redirections are not expanded.
+	///
+	/// ```
+	/// use simple_cmd::CommandBuilder;
+	///
+	/// let builder = CommandBuilder::parse("git --no-pager log --format='%H %s'").unwrap();
+	/// assert_eq!("git --no-pager log '--format=%H %s'", builder.to_string());
+	/// ```
+	pub fn parse(line: &str) -> crate::Result<CommandBuilder> {
+		let mut words = shell::split(line)?.into_iter();
+		// `split` never returns an empty vec
+		let program = words.next().unwrap_or_default();
+		Ok(CommandBuilder::new(program).args(words))
 	}
 
 	pub fn with_debug(mut self, debug: bool) -> Self {
@@ -98,6 +133,69 @@ impl CommandBuilder {
 		self
 	}
 
+	/// Sets the target of the `tracing` span wrapping every execution of the command, `simple_cmd`
+	/// by default. See [`TraceTarget`].
+	pub fn with_trace_target(mut self, target: TraceTarget) -> Self {
+		self.trace_target = target;
+		self
+	}
+
+	/// Sets the level of the `tracing` span wrapping every execution of the command, `DEBUG` by default.
+	pub fn with_trace_level(mut self, level: Level) -> Self {
+		self.trace_level = level;
+		self
+	}
+
+	/// Sets the `label` field of the `tracing` span, to tell the commands apart.
+	pub fn with_trace_label<S: Into<String>>(mut self, label: S) -> Self {
+		self.trace_label = Some(label.into());
+		self
+	}
+
+	/// Sets how the process is terminated when the timeout expires or the cancel signal is received.
+	/// By default the process is killed immediately.
+	pub fn with_termination(mut self, policy: TermPolicy) -> Self {
+		self.termination = policy;
+		self
+	}
+
+	/// Spawns the child in a new process group, with the child as the group leader.
+	pub fn process_group(mut self, value: bool) -> Self {
+		self.process_group = value;
+		self
+	}
+
+	/// On timeout or cancellation, signal the whole process group of the child instead of the
+	/// child alone, so that grandchildren are terminated as well. Implies [`CommandBuilder::process_group`].
+	pub fn kill_tree(mut self, value: bool) -> Self {
+		self.kill_tree = value;
+		self
+	}
+
+	/// Whether the command is killed when the [`crate::CmdHandle`] or the [`crate::stream::CmdStream`]
+	/// is dropped before the command has exited. Defaults to `true`.
+	pub fn kill_on_drop(mut self, value: bool) -> Self {
+		self.kill_on_drop = value;
+		self
+	}
+
+	/// When `true`, [`Cmd::output`] and `Cmd::output_async` return [`crate::Error::Timeout`] or
+	/// [`crate::Error::Cancelled`] instead of the output of a command terminated because of its timeout
+	/// or its cancel signal.
+	pub fn error_on_termination(mut self, value: bool) -> Self {
+		self.error_on_termination = value;
+		self
+	}
+
+	/// When `true`, the stderr of the command is redirected to its stdout, like `2>&1`: both are written
+	/// to the same pipe, so the stdout captured by [`Cmd::output`], [`Cmd::spawn`], [`Cmd::stream`]
+	/// and the pipelines is a transcript of the whole output, in the order it was written, and the stderr
+	/// is empty. Overrides the stdout and stderr set on the builder. Not used by [`Cmd::run`].
+	pub fn merge_stderr(mut self, value: bool) -> Self {
+		self.merge_stderr = value;
+		self
+	}
+
 	pub fn arg<S: AsRef<OsStr>>(mut self, arg: S) -> Self {
 		self.args.push(arg.as_ref().into());
 		self
@@ -108,6 +206,14 @@ impl CommandBuilder {
 		self
 	}
 
+	/// Adds an argument which is passed to the child as it is, but rendered as `****`
+	/// in the debug output, in [`Display`] and in the errors.
+	pub fn secret_arg<S: AsRef<OsStr>>(mut self, arg: S) -> Self {
+		self.redaction.args.push(self.args.len());
+		self.args.push(arg.as_ref().into());
+		self
+	}
+
 	pub fn args<I, S>(mut self, args: I) -> Self
 	where
 		I: IntoIterator<Item = S>,
@@ -130,8 +236,177 @@ impl CommandBuilder {
 		self
 	}
 
-	pub fn stdout<T: Into<Stdio>>(mut self, cfg: Option<T>) -> Self {
+	pub fn env<K, V>(mut self, key: K, val: V) -> Self
+	where
+		K: AsRef<OsStr>,
+		V: AsRef<OsStr>,
+	{
+		self.envs.push((key.as_ref().into(), Some(val.as_ref().into())));
+		self
+	}
+
+	/// Sets an environment variable whose value is rendered as `****` in the debug output.
+	pub fn secret_env<K, V>(mut self, key: K, val: V) -> Self
+	where
+		K: AsRef<OsStr>,
+		V: AsRef<OsStr>,
+	{
+		self.redaction.envs.push(key.as_ref().into());
+		self.env(key, val)
+	}
+
+	pub fn envs<I, K, V>(mut self, vars: I) -> Self
+	where
+		I: IntoIterator<Item = (K, V)>,
+		K: AsRef<OsStr>,
+		V: AsRef<OsStr>,
+	{
+		for (key, val) in vars {
+			self.envs.push((key.as_ref().into(), Some(val.as_ref().into())));
+		}
+		self
+	}
+
+	pub fn env_remove<K: AsRef<OsStr>>(mut self, key: K) -> Self {
+		self.envs.push((key.as_ref().into(), None));
+		self
+	}
+
+	/// Clears the entire environment of the child process, including any variable
+	/// previously set on this builder.
+	pub fn env_clear(mut self) -> Self {
+		self.envs.clear();
+		self.env_clear = true;
+		self
+	}
+
+	/// The child process will only inherit the given variables from the current process
+	/// environment. Variables explicitly set with [`CommandBuilder::env`] are always passed.
+	pub fn env_inherit_only<I, K>(mut self, keys: I) -> Self
+	where
+		I: IntoIterator<Item = K>,
+		K: AsRef<OsStr>,
+	{
+		self.env_inherit = Some(keys.into_iter().map(|k| k.as_ref().into()).collect());
+		self
+	}
+
+	pub fn get_envs(&self) -> impl Iterator<Item = (&OsStr, Option<&OsStr>)> {
+		self.envs.iter().map(|(k, v)| (k.as_os_str(), v.as_deref()))
+	}
+
+	/// Invokes `callback` with every line written by the command to stdout, as soon as it's read.
+	/// The line is passed without the trailing newline, and in pieces when it's longer than
+	/// [`CommandBuilder::max_line_bytes`].
+	pub fn on_stdout_line<F>(mut self, callback: F) -> Self
+	where
+		F: FnMut(&[u8]) + Send + 'static,
+	{
+		self.stdout_options.on_line = Some(sink::callback(callback));
+		self
+	}
+
+	/// Invokes `callback` with every line written by the command to stderr, as soon as it's read.
+	/// The line is passed without the trailing newline, and in pieces when it's longer than
+	/// [`CommandBuilder::max_line_bytes`].
+	pub fn on_stderr_line<F>(mut self, callback: F) -> Self
+	where
+		F: FnMut(&[u8]) + Send + 'static,
+	{
+		self.stderr_options.on_line = Some(sink::callback(callback));
+		self
+	}
+
+	/// Splits the lines longer than `max` bytes, 1 MiB by default, into pieces of `max` bytes, so that
+	/// a command writing without newlines can't make the line buffers grow without bounds.
+	/// Applies to the line callbacks, to the events of [`Cmd::stream`] and to [`Cmd::stream_json_lines`].
+	pub fn max_line_bytes(mut self, max: usize) -> Self {
+		self.stdout_options.max_line = max.max(1);
+		self.stderr_options.max_line = max.max(1);
+		self
+	}
+
+	/// Invokes `callback` with every chunk of bytes read from the stdout of the command.
+	pub fn on_stdout_chunk<F>(mut self, callback: F) -> Self
+	where
+		F: FnMut(&[u8]) + Send + 'static,
+	{
+		self.stdout_options.on_chunk = Some(sink::callback(callback));
+		self
+	}
+
+	/// Invokes `callback` with every chunk of bytes read from the stderr of the command.
+	pub fn on_stderr_chunk<F>(mut self, callback: F) -> Self
+	where
+		F: FnMut(&[u8]) + Send + 'static,
+	{
+		self.stderr_options.on_chunk = Some(sink::callback(callback));
+		self
+	}
+
+	/// Caps the stdout captured in the output to `max` bytes, dropping the rest of it according to `overflow`.
+	/// The callbacks still receive the whole output.
+	pub fn max_stdout_bytes(mut self, max: usize, overflow: Overflow) -> Self {
+		self.stdout_options.limit = Some((max, overflow));
+		self
+	}
+
+	/// Caps the stderr captured in the output to `max` bytes, dropping the rest of it according to `overflow`.
+	pub fn max_stderr_bytes(mut self, max: usize, overflow: Overflow) -> Self {
+		self.stderr_options.limit = Some((max, overflow));
+		self
+	}
+
+	/// Copies the stdout of the command to `writer`, chunk by chunk as it's read, while it's still captured.
+	///
+	/// When writing fails, the writer doesn't receive the rest of the output and [`crate::Error::Tee`],
+	/// holding the status and the output, is returned once the command has exited. The command keeps
+	/// running until then, unless [`CommandBuilder::kill_on_tee_error`] is set.
+	pub fn tee_stdout<W: Write + Send + 'static>(mut self, writer: W) -> Self {
+		self.stdout_options.tee = Some(Arc::new(Mutex::new(writer)));
+		self
+	}
+
+	/// Copies the stderr of the command to `writer`, see [`CommandBuilder::tee_stdout`].
+	pub fn tee_stderr<W: Write + Send + 'static>(mut self, writer: W) -> Self {
+		self.stderr_options.tee = Some(Arc::new(Mutex::new(writer)));
+		self
+	}
+
+	/// Copies the stdout of the command to the file at `path`, created if needed. The file is truncated
+	/// when it's opened, unless `append` is `true`.
+	pub fn tee_stdout_file<P: AsRef<Path>>(self, path: P, append: bool) -> io::Result<Self> {
+		Ok(self.tee_stdout(open_tee(path.as_ref(), append)?))
+	}
+
+	/// Copies the stderr of the command to the file at `path`, see [`CommandBuilder::tee_stdout_file`].
+	pub fn tee_stderr_file<P: AsRef<Path>>(self, path: P, append: bool) -> io::Result<Self> {
+		Ok(self.tee_stderr(open_tee(path.as_ref(), append)?))
+	}
+
+	/// When `true`, the command is killed as soon as writing to one of its tees fails.
+	/// Defaults to `false`.
+	pub fn kill_on_tee_error(mut self, kill: bool) -> Self {
+		self.stdout_options.kill_on_tee_error = kill;
+		self.stderr_options.kill_on_tee_error = kill;
+		self
+	}
+
+	/// Whether the stdout of the command is accumulated in the final output. Defaults to `true`.
+	pub fn capture_stdout(mut self, capture: bool) -> Self {
+		self.stdout_options.capture = capture;
+		self
+	}
+
+	/// Whether the stderr of the command is accumulated in the final output. Defaults to `true`.
+	pub fn capture_stderr(mut self, capture: bool) -> Self {
+		self.stderr_options.capture = capture;
+		self
+	}
+
+	pub fn stdout<T: Into<Stdio> + 'static>(mut self, cfg: Option<T>) -> Self {
 		if let Some(cfg) = cfg {
+			self.fd_stdio |= is_fd::<T>();
 			self.stdout = Some(cfg.into());
 		} else {
 			self.stdout = None;
@@ -139,8 +414,9 @@ impl CommandBuilder {
 		self
 	}
 
-	pub fn stderr<T: Into<Stdio>>(mut self, cfg: Option<T>) -> Self {
+	pub fn stderr<T: Into<Stdio> + 'static>(mut self, cfg: Option<T>) -> Self {
 		if let Some(cfg) = cfg {
+			self.fd_stdio |= is_fd::<T>();
 			self.stderr = Some(cfg.into());
 		} else {
 			self.stderr = None;
@@ -148,8 +424,33 @@ impl CommandBuilder {
 		self
 	}
 
-	pub fn stdin<T: Into<Stdio>>(mut self, cfg: Option<T>) -> Self {
+	/// Writes `data` to the stdin of the command, then closes it. The data is written again
+	/// on every attempt when the command is retried. Only used by [`Cmd::output`].
+	pub fn stdin_data<B: Into<Vec<u8>>>(mut self, data: B) -> Self {
+		self.stdin_data = Some(Arc::new(data.into()));
+		self
+	}
+
+	/// Retries the command, with [`Cmd::output`], when it fails and `policy` allows it.
+	///
+	/// Every attempt is spawned with the same stdio, set up again from the `Stdio` given to
+	/// [`CommandBuilder::stdout`] and the like. A file or a pipe given directly, like a `File` or the
+	/// `ChildStdout` of another command, would be shared by the attempts: [`Cmd::output`] fails with
+	/// [`io::ErrorKind::InvalidInput`] in that case.
+	pub fn with_retry(mut self, policy: RetryPolicy) -> Self {
+		self.retry = Some(policy);
+		self
+	}
+
+	/// Decides whether the command has succeeded, for [`Cmd::output_checked`] and the retries.
+	pub fn with_success_policy(mut self, policy: SuccessPolicy) -> Self {
+		self.success = policy;
+		self
+	}
+
+	pub fn stdin<T: Into<Stdio> + 'static>(mut self, cfg: Option<T>) -> Self {
 		if let Some(cfg) = cfg {
+			self.fd_stdio |= is_fd::<T>();
 			self.stdin = Some(cfg.into());
 		} else {
 			self.stdin = None;
@@ -163,21 +464,40 @@ impl CommandBuilder {
 	}
 
 	pub fn get_current_dir(&self) -> Option<&Path> {
-		self.cwd.as_ref().map(|cs| Path::new(cs))
+		self.cwd.as_ref().map(Path::new)
 	}
 
 	pub fn build(mut self) -> Cmd {
-		return Cmd {
+		Cmd {
 			debug: self.debug,
 			program: self.program.to_owned(),
 			args: self.args.to_owned(),
+			envs: std::mem::take(&mut self.envs),
+			env_clear: self.env_clear,
+			env_inherit: self.env_inherit.take(),
 			stdin: self.stdin.take(),
 			stdout: self.stdout.take(),
 			stderr: self.stderr.take(),
+			fd_stdio: self.fd_stdio,
 			timeout: self.timeout.take(),
 			signal: self.signal.take(),
+			termination: self.termination,
+			process_group: self.process_group,
+			kill_tree: self.kill_tree,
+			kill_on_drop: self.kill_on_drop,
+			error_on_termination: self.error_on_termination,
+			merge_stderr: self.merge_stderr,
+			stdin_data: self.stdin_data.take(),
+			retry: self.retry.take(),
+			success: std::mem::take(&mut self.success),
+			stdout_options: std::mem::take(&mut self.stdout_options),
+			stderr_options: std::mem::take(&mut self.stderr_options),
+			redaction: std::mem::take(&mut self.redaction),
+			trace_target: self.trace_target,
+			trace_level: self.trace_level,
+			trace_label: self.trace_label.take(),
 			cwd: self.cwd.take(),
-		};
+		}
 	}
 }
 
@@ -189,22 +509,38 @@ impl Cmd {
 	}
 
 	pub fn new<S: AsRef<OsStr>>(program: S) -> Self {
-		Cmd {
-			program: OsString::from(program.as_ref()),
-			cwd: None,
-			timeout: None,
-			debug: false,
-			args: vec![],
-			stdin: None,
-			stdout: None,
-			stderr: None,
-			signal: None,
-		}
+		CommandBuilder::new(program).stdout(None::<Stdio>).stderr(None::<Stdio>).build()
 	}
 
 	pub fn command(mut self) -> Command {
-		let mut command = Command::new(self.program.to_os_string());
-		command.args(self.args.clone());
+		self.build_command()
+	}
+
+	/// Builds the [`Command`] without consuming the `Cmd`, so that it can be spawned again.
+	/// The stdio configuration can only be used once: it's moved into the first `Command`.
+	pub(crate) fn build_command(&mut self) -> Command {
+		let mut command = Command::new(&self.program);
+		command.args(&self.args);
+
+		if self.env_clear {
+			command.env_clear();
+		}
+
+		if let Some(keys) = &self.env_inherit {
+			command.env_clear();
+			for key in keys {
+				if let Some(val) = std::env::var_os(key) {
+					command.env(key, val);
+				}
+			}
+		}
+
+		for (key, val) in &self.envs {
+			match val {
+				Some(val) => command.env(key, val),
+				None => command.env_remove(key),
+			};
+		}
 
 		if let Some(stdin) = self.stdin.take() {
 			command.stdin(stdin);
@@ -218,364 +554,379 @@ impl Cmd {
 			command.stderr(stderr);
 		}
 
-		if let Some(cwd) = self.cwd.take() {
+		if let Some(cwd) = &self.cwd {
 			command.current_dir(cwd);
 		}
 
+		if self.process_group || self.kill_tree {
+			command.process_group(0);
+		}
+
 		command
 	}
 
 	// endregion public methods
 
 	pub fn run(mut self) -> crate::Result<Option<ExitStatus>> {
+		let span = CmdSpan::new(&self, "run");
+		let _enter = span.span().enter();
+
 		if self.debug {
 			self.debug();
 		}
 
+		let redaction = std::mem::take(&mut self.redaction);
 		let mut command = self.command();
-		let mut child = command.spawn().unwrap();
+		let mut child = spawn(&mut command, &redaction, false)?;
 		drop(command);
-		child.try_wait().map_err(|e| crate::Error::IoError(e))
+		span.record_pid(child.id());
+
+		let status = child.try_wait().map_err(crate::Error::IoError)?;
+		span.record_exit(status.as_ref());
+		Ok(status)
+	}
+
+	pub fn output(mut self) -> crate::Result<CmdOutput> {
+		let output = match self.retry.take() {
+			Some(policy) => self.output_with_retry(&policy)?,
+			None => self.wait_for_output()?,
+		};
+
+		if self.error_on_termination {
+			output.check_termination()
+		} else {
+			Ok(output)
+		}
 	}
 
-	pub fn output(self) -> crate::Result<Output> {
-		self.wait_for_output()
+	/// Runs the command once. The `Cmd` is left as it is, apart from its stdio, so that it can be run again.
+	pub(crate) fn wait_for_output(&mut self) -> crate::Result<CmdOutput> {
+		let mut command = self.build_command();
+		self.output_of(&mut command)
 	}
 
-	pub(crate) fn wait_for_output(mut self) -> crate::Result<Output> {
-		let has_debug = self.debug;
-		if has_debug {
+	/// Runs `command`, built from this `Cmd`, once. The same `command` can be run again, with the same stdio.
+	pub(crate) fn output_of(&mut self, command: &mut Command) -> crate::Result<CmdOutput> {
+		let span = CmdSpan::new(self, "output");
+		let _enter = span.span().enter();
+
+		if self.debug {
 			self.debug();
 		}
 
-		let cancel_signal = self.signal.take();
-		let ticks = self.timeout.take().map(|t| tick(t));
+		let command_line = self.as_string();
+		let supervisor = self.supervisor();
+		let cause = Arc::clone(&supervisor.cause);
+		let kill_tree = self.kill_tree;
+		let mut stdout_options = self.stdout_options.clone();
+		let mut stderr_options = self.stderr_options.clone();
 
-		let mut command = self.command();
-		let mut child = command.spawn().unwrap();
+		if self.stdin_data.is_some() {
+			command.stdin(Stdio::piped());
+		}
+		let started_at = SystemTime::now();
+		let start = Instant::now();
+		let mut child = spawn(command, &self.redaction, self.merge_stderr)?;
 
+		let stdin = child.stdin.take();
 		let stdout = child.stdout.take();
 		let stderr = child.stderr.take();
 
-		let status_receiver = Arc::new((Mutex::new(None), Condvar::new()));
-		let status_receiver_cloned = Arc::clone(&status_receiver);
-
-		drop(command);
-
-		let local_thread = std::thread::Builder::new().name("cmd_wait".to_string()).spawn(move || {
-			let (lock, condvar) = &*status_receiver_cloned;
-			let mut status_mutex = lock.lock().unwrap();
-
-			let mut sel = Select::new();
-			let mut oper_cancel: Option<usize> = None;
-			let mut oper_timeout: Option<usize> = None;
-
-			if cancel_signal.is_some() {
-				oper_cancel = Some(sel.recv(cancel_signal.as_ref().unwrap()));
-			}
-
-			if ticks.is_some() {
-				oper_timeout = Some(sel.recv(ticks.as_ref().unwrap()));
-			}
+		let pid = child.id();
+		span.record_pid(pid);
+		let waiter = Arc::new(ChildWaiter::new(child, kill_tree)?);
+		let waiters = std::slice::from_ref(&waiter);
 
-			let mut killed = false;
-
-			loop {
-				match sel.try_ready() {
-					Err(_) => {
-						if let Ok(Some(status)) = child.try_wait() {
-							*status_mutex = Some(status);
-							condvar.notify_one();
-							break;
-						}
-					}
-
-					Ok(i) if !killed && oper_cancel.is_some() && i == oper_cancel.unwrap() => {
-						if has_debug {
-							warn!("ctrl+c received");
-						}
-						sel.remove(oper_cancel.unwrap());
-						let _ = child.kill();
-						killed = true;
-					}
-
-					Ok(i) if !killed && oper_timeout.is_some() && i == oper_timeout.unwrap() => {
-						if has_debug {
-							warn!("command timeout! killing the process...");
-						}
-						sel.remove(oper_timeout.unwrap());
-						let _ = child.kill();
-						killed = true;
-					}
-
-					Ok(i) => {
-						if has_debug {
-							warn!("Invalid operation index {i}!");
-						}
-						break;
-					}
-				}
-			}
-		})?;
+		let stdin_thread = match (stdin, self.stdin_data.clone()) {
+			(Some(stdin), Some(data)) => Some(or_kill_all(write_stdin(stdin, data), waiters)?),
+			_ => None,
+		};
+		let supervisor_thread = or_kill_all(supervisor.spawn(vec![Arc::clone(&waiter)]), waiters)?;
 
 		// start collecting the stdout and stderr from the child process
-		let output = Cmd::read_to_end(stdout, stderr);
-
-		// wait for the local thread to complete
-		if let Err(_err) = local_thread.join() {
+		let kill = kill_hook(&waiter, Some(Arc::clone(&cause)));
+		stdout_options.kill = Some(Arc::clone(&kill));
+		stderr_options.kill = Some(kill);
+
+		let output = read_to_end(stdout, stderr, stdout_options, stderr_options);
+		waiter.release();
+		let status = waiter.wait();
+		let elapsed = start.elapsed();
+		span.record_exit(status.as_ref().ok());
+
+		// the command may exit without reading its stdin
+		if let Some(Err(_err)) = stdin_thread.map(|t| t.join()) {
 			warn!("failed to join the thread!");
 		}
 
-		// Wait for the thread to complete.
-		let (lock, cvar) = &*status_receiver;
-		let mut status = lock.lock().unwrap();
-		while status.is_none() {
-			(status, _) = cvar.wait_timeout(status, Duration::from_secs(1)).unwrap();
-			break;
-			//status = cvar.wait(status).unwrap();
+		if let Some(Err(_err)) = supervisor_thread.map(|t| t.join()) {
+			warn!("failed to join the thread!");
 		}
 
-		//trace!("final exit status is: {status:?}");
-
-		match output {
-			Ok(output) => Ok(Output {
-				status: status.unwrap(),
-				stdout: output.0,
-				stderr: output.1,
-			}),
-			Err(e) => Err(e),
-		}
+		let (mut stdout, mut stderr) = output?;
+		let tee_failure = tee_failure(&mut stdout, &mut stderr);
+		let status = status?;
+		let output = CmdOutput {
+			status,
+			stdout: stdout.bytes,
+			stderr: stderr.bytes,
+			stdout_truncated: stdout.truncated,
+			stderr_truncated: stderr.truncated,
+			pid,
+			started_at,
+			elapsed,
+			command_line,
+			termination: Termination::from_status(&status, cause.get().copied()),
+			attempts: 1,
+		};
+		check_tee(output, tee_failure)
 	}
 
+	/// Reads both streams to the end. `stderr` is drained on a separate thread so that a child
+	/// filling up one pipe while we are blocked on the other cannot deadlock.
 	pub fn read_to_end(stdout: Option<ChildStdout>, stderr: Option<ChildStderr>) -> crate::Result<(Vec<u8>, Vec<u8>)> {
-		let mut stdout_writer: Vec<u8> = Vec::new();
-		let mut stderr_writer: Vec<u8> = Vec::new();
-
-		if let Some(stdout) = stdout {
-			let stdout_reader = BufReader::new(stdout);
-			for line in <BufReader<ChildStdout> as BufReaderExt<BufReader<ChildStdout>>>::lines_vec(stdout_reader) {
-				stdout_writer.extend(line?);
-			}
-		}
-
-		if let Some(stderr) = stderr {
-			let stderr_reader = BufReader::new(stderr);
-			for line in <BufReader<ChildStderr> as BufReaderExt<BufReader<ChildStderr>>>::lines_vec(stderr_reader) {
-				stderr_writer.extend(line?);
-			}
-		}
+		let (stdout, stderr) = read_to_end(stdout, stderr, StreamOptions::default(), StreamOptions::default())?;
+		Ok((stdout.bytes, stderr.bytes))
+	}
 
-		Ok((stdout_writer, stderr_writer))
+	pub(crate) fn take_stream_options(&mut self) -> (StreamOptions, StreamOptions) {
+		(
+			std::mem::take(&mut self.stdout_options),
+			std::mem::take(&mut self.stderr_options),
+		)
 	}
 
-	pub fn pipe<T>(mut self, cmd2: T) -> Result<Output, Error>
+	/// Pipes the stdout of this command into `cmd2`. The timeout, the cancel signal and the termination
+	/// policy of this command apply to the whole pipeline, while both commands keep the rest of their
+	/// configuration. More commands can be chained with [`Pipeline::pipe`], then the pipeline is run
+	/// with [`Pipeline::output`].
+	pub fn pipe<T>(mut self, cmd2: T) -> Pipeline
 	where
-		T: Into<Command>,
+		T: Into<Cmd>,
 	{
-		let mut other = cmd2.into();
-
-		if self.debug {
-			let s1 = self.as_string();
-			let s2 = other.as_string();
-			trace!("Executing `{s1} | {s2}`...");
+		Pipeline::new()
+			.with_debug(self.debug)
+			.timeout(self.timeout.take())
+			.signal(self.signal.take())
+			.with_termination(self.termination)
+			.stage(self)
+			.stage(cmd2)
+	}
+
+	pub(crate) fn supervisor(&mut self) -> Supervisor {
+		Supervisor {
+			cancel: self.signal.clone(),
+			timeout: self.timeout,
+			termination: self.termination,
+			debug: self.debug,
+			span: Span::current(),
+			cause: Default::default(),
 		}
+	}
+}
 
-		let cancel_signal = self.signal.take();
-		let ticks = self.timeout.take().map(|t| tick(t));
-
-		let mut command1 = self.command();
-		let mut child1 = command1.spawn().unwrap();
-
-		let child1_stdout: ChildStdout = child1
-			.stdout
-			.take()
-			.ok_or(io::Error::new(ErrorKind::InvalidData, "child stdout unavailable"))?;
-
-		let fd: Stdio = child1_stdout.try_into().unwrap();
-
-		other.stdin(fd);
+impl TermPolicy {
+	pub fn new(signal: i32, grace: Duration, then: i32) -> Self {
+		TermPolicy { signal, grace, then }
+	}
 
-		let mut child2 = other.spawn().unwrap();
+	/// Kill the process immediately with `SIGKILL`. This is the default policy.
+	pub fn kill() -> Self {
+		TermPolicy::new(signal_hook::consts::SIGKILL, Duration::ZERO, signal_hook::consts::SIGKILL)
+	}
 
-		let stdout = child2.stdout.take();
-		let stderr = child2.stderr.take();
+	/// Send `SIGTERM` first, then `SIGKILL` if the process is still alive after `grace`.
+	pub fn graceful(grace: Duration) -> Self {
+		TermPolicy::new(signal_hook::consts::SIGTERM, grace, signal_hook::consts::SIGKILL)
+	}
 
-		let status_receiver = Arc::new((Mutex::new(None), Condvar::new()));
-		let status_receiver_cloned = Arc::clone(&status_receiver);
+	/// Returns a receiver which fires when the `then` signal must be sent.
+	pub(crate) fn escalation(&self) -> Receiver<Instant> {
+		if self.signal == self.then {
+			never()
+		} else {
+			after(self.grace)
+		}
+	}
+}
 
-		drop(command1);
-		drop(other);
+pub(crate) fn read_to_end(
+	stdout: Option<ChildStdout>,
+	stderr: Option<ChildStderr>,
+	stdout_options: StreamOptions,
+	stderr_options: StreamOptions,
+) -> crate::Result<(Captured, Captured)> {
+	let stderr_thread = match stderr {
+		Some(stderr) => Some(
+			std::thread::Builder::new()
+				.name("cmd_stderr".to_string())
+				.spawn(move || OutputSink::new(stderr_options).read_from(stderr))?,
+		),
+		None => None,
+	};
+
+	let stdout_result = match stdout {
+		Some(stdout) => OutputSink::new(stdout_options).read_from(stdout),
+		None => Ok(Captured::default()),
+	};
+
+	let stderr_result = match stderr_thread {
+		Some(handle) => handle
+			.join()
+			.unwrap_or_else(|_| Err(io::Error::other("stderr reader thread panicked"))),
+		None => Ok(Captured::default()),
+	};
+
+	Ok((stdout_result?, stderr_result?))
+}
 
-		let local_thread = std::thread::Builder::new().name("cmd_wait".to_string()).spawn(move || {
-			let (lock, condvar) = &*status_receiver_cloned;
-			let mut status_mutex = lock.lock().unwrap();
+fn open_tee(path: &Path, append: bool) -> io::Result<File> {
+	OpenOptions::new()
+		.create(true)
+		.write(true)
+		.append(append)
+		.truncate(!append)
+		.open(path)
+}
 
-			let mut sel = Select::new();
-			let mut oper_cancel: Option<usize> = None;
-			let mut oper_timeout: Option<usize> = None;
+/// Writes `data` to the stdin of the child from a separate thread, then closes it.
+fn write_stdin(mut stdin: ChildStdin, data: Arc<Vec<u8>>) -> io::Result<JoinHandle<()>> {
+	std::thread::Builder::new().name("cmd_stdin".to_string()).spawn(move || {
+		// the command is free not to read all of it
+		let _ = stdin.write_all(&data);
+	})
+}
 
-			if cancel_signal.is_some() {
-				oper_cancel = Some(sel.recv(cancel_signal.as_ref().unwrap()));
-			}
+/// Spawns the command. With `merge_stderr`, the stdout of the child reads both its stdout and its stderr.
+pub(crate) fn spawn(command: &mut Command, redaction: &Redaction, merge_stderr: bool) -> crate::Result<Child> {
+	let merged = if merge_stderr { Some(merge_output(command)?) } else { None };
+	let mut child = command.spawn().map_err(|err| crate::Error::spawn(command, redaction, err))?;
+	if let Some(reader) = merged {
+		// the writing ends must be closed in the parent, even if `command` is spawned again
+		command.stdout(Stdio::null()).stderr(Stdio::null());
+		child.stdout = Some(ChildStdout::from(OwnedFd::from(reader)));
+	}
+	Ok(child)
+}
 
-			if ticks.is_some() {
-				oper_timeout = Some(sel.recv(ticks.as_ref().unwrap()));
-			}
+/// Whether a stdio of type `T` is an open file or pipe, e.g. a `File` or a `ChildStdout`, rather than a `Stdio`.
+fn is_fd<T: 'static>() -> bool {
+	TypeId::of::<T>() != TypeId::of::<Stdio>()
+}
 
-			let mut killed = false;
-
-			loop {
-				match sel.try_ready() {
-					Err(_) => {
-						if let Ok(Some(status)) = child2.try_wait() {
-							//warn!("exit status received:/**/ {:?}", status);
-							let _ = child1.kill();
-							*status_mutex = Some(status);
-							condvar.notify_one();
-							break;
-						}
-
-						if !killed {
-							if let Ok(Some(_status1)) = child1.try_wait() {
-								//warn!("[1] exit status received: {:?}", status1);
-								if let Ok(Some(_status)) = child2.try_wait() {
-									//warn!("[2] exit status received: {:?}", _status);
-									killed = true;
-								} else {
-									//warn!("killing child2..");
-									//let _ = child2.kill();
-									killed = true;
-								}
-							}
-						}
-					}
-
-					Ok(i) if !killed && oper_cancel.is_some() && i == oper_cancel.unwrap() => {
-						sel.remove(oper_cancel.unwrap());
-						let _ = child1.kill();
-						let _ = child2.kill();
-						killed = true;
-					}
-
-					Ok(i) if !killed && oper_timeout.is_some() && i == oper_timeout.unwrap() => {
-						sel.remove(oper_timeout.unwrap());
-						let _ = child1.kill();
-						let _ = child2.kill();
-						killed = true;
-					}
-
-					Ok(i) => {
-						error!("Invalid operation index {i}!");
-						break;
-					}
-				}
-			}
-		})?;
+/// Points the stdout and the stderr of `command` to the same pipe, returning its reading end.
+/// The writing ends are closed in the parent when `command` is dropped.
+pub(crate) fn merge_output(command: &mut Command) -> io::Result<PipeReader> {
+	let (reader, writer) = io::pipe()?;
+	command.stdout(writer.try_clone()?);
+	command.stderr(writer);
+	Ok(reader)
+}
 
-		// start collecting the stdout and stderr from the child process
-		let output = Cmd::read_to_end(stdout, stderr);
+impl Vec8ToString for Vec<u8> {
+	fn as_str(&self) -> Option<&str> {
+		std::str::from_utf8(self).ok()
+	}
 
-		// wait for the local thread to complete
-		if let Err(_err) = local_thread.join() {
-			warn!("failed to join the thread!");
-		}
+	fn as_str_lossy(&self) -> Cow<'_, str> {
+		String::from_utf8_lossy(self)
+	}
 
-		// Wait for the thread to complete.
-		let (lock, cvar) = &*status_receiver;
-		let mut status = lock.lock().unwrap();
-		while status.is_none() {
-			(status, _) = cvar.wait_timeout(status, Duration::from_secs(1)).unwrap();
-			break;
-		}
+	fn trimmed(&self) -> String {
+		self.as_str_lossy().trim().to_string()
+	}
 
-		match output {
-			Ok(output) => Ok(Output {
-				status: status.unwrap(),
-				stdout: output.0,
-				stderr: output.1,
-			}),
-			Err(e) => Err(e),
-		}
+	fn lines_lossy(&self) -> Vec<String> {
+		self.as_str_lossy().lines().map(String::from).collect()
 	}
-}
 
-impl Vec8ToString for Vec<u8> {
-	fn as_str(&self) -> Option<&str> {
-		match std::str::from_utf8(self) {
-			Ok(s) => Some(s),
-			Err(_) => None,
-		}
+	fn crlf_normalized(&self) -> String {
+		self.as_str_lossy().replace("\r\n", "\n")
 	}
-}
 
-pub(crate) trait BufReaderExt<B: BufRead> {
-	fn lines_vec(self) -> LinesVec<Self>
-	where
-		Self: Sized;
+	fn ansi_stripped(&self) -> String {
+		text::strip_ansi(&self.as_str_lossy())
+	}
 }
 
 pub struct LinesVec<B> {
 	buf: B,
 }
 
-impl<B: BufRead, R> BufReaderExt<B> for BufReader<R> {
-	fn lines_vec(self) -> LinesVec<Self>
-	where
-		Self: Sized,
-	{
-		LinesVec { buf: self }
+impl<B: BufRead> LinesVec<B> {
+	pub(crate) fn new(buf: B) -> Self {
+		LinesVec { buf }
 	}
 }
 
+/// Lines longer than [`MAX_LINE_BYTES`] are returned in pieces.
 impl<B: BufRead> Iterator for LinesVec<B> {
 	type Item = io::Result<Vec<u8>>;
 
 	fn next(&mut self) -> Option<std::io::Result<Vec<u8>>> {
 		let mut buf = Vec::new();
-		match self.buf.read_until(b'\n', &mut buf) {
-			Ok(0) => None,
-			Ok(_n) => Some(Ok(buf)),
-			Err(e) => Some(Err(e)),
+		loop {
+			let available = match self.buf.fill_buf() {
+				Ok(available) => available,
+				Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+				Err(e) => return Some(Err(e)),
+			};
+			if available.is_empty() {
+				break;
+			}
+
+			let available = &available[..available.len().min(MAX_LINE_BYTES - buf.len())];
+			let (used, done) = match available.iter().position(|b| *b == b'\n') {
+				Some(index) => (index + 1, true),
+				None => (available.len(), buf.len() + available.len() == MAX_LINE_BYTES),
+			};
+			buf.extend_from_slice(&available[..used]);
+			self.buf.consume(used);
+			if done {
+				break;
+			}
+		}
+
+		if buf.is_empty() {
+			None
+		} else {
+			Some(Ok(buf))
 		}
 	}
 }
 
-impl From<CommandBuilder> for Command {
+impl From<CommandBuilder> for Cmd {
 	fn from(value: CommandBuilder) -> Self {
-		let mut command = Command::new(value.program.to_os_string());
-		command.args(value.args.to_vec());
+		value.build()
+	}
+}
 
-		if let Some(stdin) = value.stdin {
-			command.stdin(Stdio::from(stdin));
+/// Only the program, the arguments, the environment and the working directory are kept:
+/// the stdio configuration of a `Command` can't be read back.
+impl From<Command> for Cmd {
+	fn from(value: Command) -> Self {
+		let mut builder = CommandBuilder::new(value.get_program()).args(value.get_args());
+		for (key, val) in value.get_envs() {
+			builder = match val {
+				Some(val) => builder.env(key, val),
+				None => builder.env_remove(key),
+			};
 		}
-
-		if let Some(stdout) = value.stdout {
-			command.stdout(Stdio::from(stdout));
+		if let Some(cwd) = value.get_current_dir() {
+			builder = builder.current_dir(cwd);
 		}
+		builder.build()
+	}
+}
 
-		if let Some(stderr) = value.stderr {
-			command.stderr(Stdio::from(stderr));
-		}
-		command
+impl From<CommandBuilder> for Command {
+	fn from(value: CommandBuilder) -> Self {
+		value.build().command()
 	}
 }
 
 impl From<Cmd> for Command {
 	fn from(value: Cmd) -> Self {
-		let mut command = Command::new(value.program.to_os_string());
-		command.args(value.args.to_vec());
-
-		if let Some(stdin) = value.stdin {
-			command.stdin(Stdio::from(stdin));
-		}
-
-		if let Some(stdout) = value.stdout {
-			command.stdout(Stdio::from(stdout));
-		}
-
-		if let Some(stderr) = value.stderr {
-			command.stderr(Stdio::from(stderr));
-		}
-		command
+		value.command()
 	}
 }
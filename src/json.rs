@@ -0,0 +1,113 @@
+use std::marker::PhantomData;
+use std::process::ExitStatus;
+
+use serde::de::DeserializeOwned;
+
+use crate::debug::CommandDebug;
+use crate::errors::CmdError;
+use crate::stream::{CmdStream, Event};
+use crate::{Cmd, SuccessPolicy};
+
+/// How many bytes of the output are kept on both sides of a parse error, see [`crate::Error::Json`].
+const SNIPPET_BYTES: usize = 40;
+
+impl Cmd {
+	/// Runs the command with [`Cmd::output`] and deserializes its stdout from JSON, if it has succeeded
+	/// according to its [`SuccessPolicy`].
+	pub fn output_json<T: DeserializeOwned>(self) -> crate::Result<T> {
+		let policy = self.success.clone();
+		let output = self.output()?;
+
+		if !policy.accepts(&output.status, &output.stderr) {
+			return Err(CmdError::from(output).into());
+		}
+
+		serde_json::from_slice(&output.stdout)
+			.map_err(|err| json_error(output.command_line, Some(output.status), &output.stdout, err))
+	}
+
+	/// Spawns the command with [`Cmd::stream`] and deserializes every line of its stdout from JSON,
+	/// as it's produced. See [`JsonLines`].
+	pub fn stream_json_lines<T: DeserializeOwned>(self) -> crate::Result<JsonLines<T>> {
+		let policy = self.success.clone();
+		let command_line = self.as_string();
+		Ok(JsonLines {
+			stream: self.stream()?,
+			policy,
+			command_line,
+			item: PhantomData,
+		})
+	}
+}
+
+/// Iterator over the values written by a command as newline-delimited JSON, returned by
+/// [`Cmd::stream_json_lines`].
+///
+/// The blank lines and the stderr are skipped. A line which can't be deserialized is returned as
+/// an error, and the iteration goes on with the next one. When the command exits with a code
+/// not accepted by its [`SuccessPolicy`], the last item is a [`crate::Error::CommandError`]:
+/// the stderr is not captured, so it's not checked by the policy.
+///
+/// A line longer than [`crate::CommandBuilder::max_line_bytes`] is split like the [`Event`]s of the stream,
+/// and its pieces fail to be deserialized: the cap must be raised for larger values.
+#[derive(Debug)]
+pub struct JsonLines<T> {
+	stream: CmdStream,
+	policy: SuccessPolicy,
+	command_line: String,
+	item: PhantomData<fn() -> T>,
+}
+
+impl<T> JsonLines<T> {
+	/// Returns the pid of the command.
+	pub fn id(&self) -> u32 {
+		self.stream.id()
+	}
+}
+
+impl<T: DeserializeOwned> Iterator for JsonLines<T> {
+	type Item = crate::Result<T>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		loop {
+			match self.stream.next()? {
+				Ok(Event::Stdout(line)) if line.trim_ascii().is_empty() => continue,
+				Ok(Event::Stdout(line)) => {
+					return Some(
+						serde_json::from_slice(&line)
+							.map_err(|err| json_error(self.command_line.clone(), None, &line, err)),
+					)
+				}
+				Ok(Event::Stderr(_)) => continue,
+				Ok(Event::Exit(status)) if self.policy.accepts(&status, &[]) => return None,
+				Ok(Event::Exit(status)) => {
+					let err = CmdError {
+						command: Some(self.command_line.clone()),
+						..CmdError::from_status(status)
+					};
+					return Some(Err(err.into()));
+				}
+				Err(err) => return Some(Err(err.into())),
+			}
+		}
+	}
+}
+
+fn json_error(command: String, status: Option<ExitStatus>, input: &[u8], source: serde_json::Error) -> crate::Error {
+	crate::Error::Json {
+		command,
+		status,
+		snippet: snippet(input, source.line(), source.column()),
+		source,
+	}
+}
+
+/// The bytes of `input` around the 1-based `line` and `column` of an error, decoded lossily.
+fn snippet(input: &[u8], line: usize, column: usize) -> String {
+	let line = input.split(|b| *b == b'\n').nth(line.saturating_sub(1)).unwrap_or_default();
+	let line = line.strip_suffix(b"\r").unwrap_or(line);
+	let column = column.min(line.len());
+	let start = column.saturating_sub(SNIPPET_BYTES);
+	let end = (column + SNIPPET_BYTES).min(line.len());
+	String::from_utf8_lossy(&line[start..end]).into_owned()
+}
@@ -1,19 +1,51 @@
 #![doc = include_str!("../README.md")]
 
+use std::borrow::Cow;
 use std::ffi::OsString;
 use std::process::Stdio;
+use std::sync::Arc;
 use std::time::Duration;
 
 use crossbeam::channel::Receiver;
 use thiserror::Error;
+use tracing::Level;
 
+use crate::debug::Redaction;
 use crate::errors::CmdError;
+use crate::sink::StreamOptions;
 
+#[cfg(feature = "tokio")]
+mod asynchronous;
 pub mod debug;
 pub mod errors;
+mod handle;
 mod impls;
+#[cfg(feature = "serde")]
+mod json;
+mod output;
+mod pipeline;
 pub mod prelude;
+mod retry;
+mod shell;
+mod sink;
+mod span;
+pub mod stream;
+mod success;
 mod test;
+mod text;
+mod waiter;
+
+#[cfg(feature = "tokio")]
+pub use crate::asynchronous::AsyncCmdHandle;
+pub use crate::handle::CmdHandle;
+#[cfg(feature = "serde")]
+pub use crate::json::JsonLines;
+pub use crate::output::{CmdOutput, Termination};
+pub use crate::pipeline::{Pipeline, PipelineOutput};
+pub use crate::retry::{Backoff, RetryPolicy};
+pub use crate::sink::Overflow;
+pub use crate::span::TraceTarget;
+pub use crate::success::SuccessPolicy;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -24,39 +56,155 @@ pub enum Error {
 
 	#[error(transparent)]
 	IoError(#[from] std::io::Error),
+
+	#[error("failed to spawn `{}`: {source}", program.to_string_lossy())]
+	Spawn {
+		program: OsString,
+		args: Vec<OsString>,
+		cwd: Option<OsString>,
+		#[source]
+		source: std::io::Error,
+	},
+
+	#[error("command timed out after {elapsed:?}")]
+	Timeout {
+		elapsed: Duration,
+		stdout: Vec<u8>,
+		stderr: Vec<u8>,
+	},
+
+	#[error("command cancelled after {elapsed:?}")]
+	Cancelled {
+		elapsed: Duration,
+		stdout: Vec<u8>,
+		stderr: Vec<u8>,
+	},
+
+	/// Writing the output of the command to a tee has failed. The command has run to the end, unless
+	/// [`CommandBuilder::kill_on_tee_error`] is set, and its output is kept.
+	#[error("failed to write the {stream} of the command to its tee: {source}")]
+	Tee {
+		stream: &'static str,
+		#[source]
+		source: std::io::Error,
+		status: std::process::ExitStatus,
+		stdout: Vec<u8>,
+		stderr: Vec<u8>,
+	},
+
+	/// The output of the command isn't the JSON expected, see [`Cmd::output_json`].
+	/// `status` is `None` while the command is still running.
+	#[cfg(feature = "serde")]
+	#[error("failed to parse the output of `{command}` as JSON: {source}, near `{snippet}`")]
+	Json {
+		command: String,
+		status: Option<std::process::ExitStatus>,
+		snippet: String,
+		#[source]
+		source: serde_json::Error,
+	},
+
+	#[error("failed to parse `{input}`: {reason}")]
+	Parse { input: String, reason: String },
 }
 
-#[derive(Debug)]
 pub struct Cmd {
 	pub(crate) debug: bool,
 	pub(crate) program: OsString,
 	pub(crate) args: Vec<OsString>,
 	pub(crate) cwd: Option<OsString>,
+	pub(crate) envs: Vec<(OsString, Option<OsString>)>,
+	pub(crate) env_clear: bool,
+	pub(crate) env_inherit: Option<Vec<OsString>>,
 	pub(crate) stdin: Option<Stdio>,
 	pub(crate) stdout: Option<Stdio>,
 	pub(crate) stderr: Option<Stdio>,
+	/// A stream was set to an open file or pipe, which would be shared by all the attempts of a retried command.
+	pub(crate) fd_stdio: bool,
 	pub(crate) timeout: Option<Duration>,
 	pub(crate) signal: Option<Receiver<()>>,
+	pub(crate) termination: TermPolicy,
+	pub(crate) process_group: bool,
+	pub(crate) kill_tree: bool,
+	pub(crate) kill_on_drop: bool,
+	pub(crate) error_on_termination: bool,
+	pub(crate) merge_stderr: bool,
+	pub(crate) stdin_data: Option<Arc<Vec<u8>>>,
+	pub(crate) retry: Option<RetryPolicy>,
+	pub(crate) success: SuccessPolicy,
+	pub(crate) stdout_options: StreamOptions,
+	pub(crate) stderr_options: StreamOptions,
+	pub(crate) redaction: Redaction,
+	pub(crate) trace_target: TraceTarget,
+	pub(crate) trace_level: Level,
+	pub(crate) trace_label: Option<String>,
 }
 
-#[derive(Debug)]
 pub struct CommandBuilder {
 	pub(crate) debug: bool,
 	pub(crate) program: OsString,
 	pub(crate) cwd: Option<OsString>,
 	pub(crate) args: Vec<OsString>,
+	pub(crate) envs: Vec<(OsString, Option<OsString>)>,
+	pub(crate) env_clear: bool,
+	pub(crate) env_inherit: Option<Vec<OsString>>,
 	pub(crate) stdin: Option<Stdio>,
 	pub(crate) stdout: Option<Stdio>,
 	pub(crate) stderr: Option<Stdio>,
+	/// A stream was set to an open file or pipe, which would be shared by all the attempts of a retried command.
+	pub(crate) fd_stdio: bool,
 	pub(crate) timeout: Option<Duration>,
 	pub(crate) signal: Option<Receiver<()>>,
+	pub(crate) termination: TermPolicy,
+	pub(crate) process_group: bool,
+	pub(crate) kill_tree: bool,
+	pub(crate) kill_on_drop: bool,
+	pub(crate) error_on_termination: bool,
+	pub(crate) merge_stderr: bool,
+	pub(crate) stdin_data: Option<Arc<Vec<u8>>>,
+	pub(crate) retry: Option<RetryPolicy>,
+	pub(crate) success: SuccessPolicy,
+	pub(crate) stdout_options: StreamOptions,
+	pub(crate) stderr_options: StreamOptions,
+	pub(crate) redaction: Redaction,
+	pub(crate) trace_target: TraceTarget,
+	pub(crate) trace_level: Level,
+	pub(crate) trace_label: Option<String>,
 }
 
-pub(crate) trait OutputResult {
-	fn to_result(&self) -> Result<Vec<u8>>;
-	fn try_to_result(&self) -> Result<Vec<u8>>;
+/// Describes how a command is terminated when its timeout expires or the cancel signal is received.
+///
+/// `signal` is sent first, and if the process is still alive after the `grace` period
+/// it is sent the `then` signal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TermPolicy {
+	pub signal: i32,
+	pub grace: Duration,
+	pub then: i32,
+}
+
+/// Turns the output of a command into its stdout, or into an error if the command has failed
+/// according to the [`SuccessPolicy`].
+pub trait OutputResult {
+	fn into_result(self, policy: &SuccessPolicy) -> Result<Vec<u8>>;
 }
 
 pub trait Vec8ToString {
+	/// The bytes as a string, or `None` if they aren't valid UTF-8.
 	fn as_str(&self) -> Option<&str>;
+
+	/// The bytes as a string, with the invalid UTF-8 sequences replaced by `U+FFFD`.
+	fn as_str_lossy(&self) -> Cow<'_, str>;
+
+	/// The lossy string, with its leading and trailing whitespace removed.
+	fn trimmed(&self) -> String;
+
+	/// The lines of the lossy string, without their `\n` or `\r\n` terminator.
+	fn lines_lossy(&self) -> Vec<String>;
+
+	/// The lossy string, with every `\r\n` replaced by `\n`.
+	fn crlf_normalized(&self) -> String;
+
+	/// The lossy string, without the terminal escape sequences (colors, cursor movements, ...).
+	fn ansi_stripped(&self) -> String;
 }
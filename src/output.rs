@@ -0,0 +1,106 @@
+use std::os::unix::process::ExitStatusExt;
+use std::process::{ExitStatus, Output};
+use std::time::{Duration, SystemTime};
+
+use crate::errors::CmdError;
+
+/// Why a command has stopped running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Termination {
+	/// The command exited on its own, with an exit code.
+	Exited,
+	/// The command was terminated because its timeout expired.
+	TimedOut,
+	/// The command was terminated because the cancel signal was received.
+	Cancelled,
+	/// The command was killed because it exceeded the maximum size of its output,
+	/// see [`crate::Overflow::Kill`].
+	OutputLimitExceeded,
+	/// The command was killed because writing its output to a tee failed,
+	/// see [`crate::CommandBuilder::kill_on_tee_error`].
+	TeeFailed,
+	/// The command was terminated by a signal which wasn't sent by this crate.
+	Signaled(i32),
+}
+
+impl Termination {
+	/// The cause recorded by the supervisor wins over the exit status: a timed out command
+	/// is reported as such even if it handled the signal and exited with a code.
+	pub(crate) fn from_status(status: &ExitStatus, cause: Option<Termination>) -> Self {
+		match (cause, status.signal()) {
+			(Some(cause), _) => cause,
+			(None, Some(signal)) => Termination::Signaled(signal),
+			(None, None) => Termination::Exited,
+		}
+	}
+}
+
+/// The output of [`crate::Cmd::output`] and [`crate::CmdHandle::wait_with_output`] (and of their async
+/// versions): a [`std::process::Output`] along with how and when the command ran.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CmdOutput {
+	pub status: ExitStatus,
+	pub stdout: Vec<u8>,
+	pub stderr: Vec<u8>,
+	/// Part of the stdout was dropped, see [`crate::CommandBuilder::max_stdout_bytes`].
+	pub stdout_truncated: bool,
+	/// Part of the stderr was dropped, see [`crate::CommandBuilder::max_stderr_bytes`].
+	pub stderr_truncated: bool,
+	pub pid: u32,
+	/// When the command was spawned.
+	pub started_at: SystemTime,
+	/// The wall time elapsed from the spawn of the command to its exit.
+	pub elapsed: Duration,
+	/// The command line, as rendered by [`crate::debug::CommandDebug::as_string`].
+	pub command_line: String,
+	pub termination: Termination,
+	/// How many times the command was run, see [`crate::RetryPolicy`].
+	pub attempts: u32,
+}
+
+impl CmdOutput {
+	/// Turns the output of a command terminated because of its timeout or its cancel signal into an error,
+	/// keeping the output captured so far.
+	pub(crate) fn check_termination(self) -> crate::Result<CmdOutput> {
+		match self.termination {
+			Termination::TimedOut => Err(crate::Error::Timeout {
+				elapsed: self.elapsed,
+				stdout: self.stdout,
+				stderr: self.stderr,
+			}),
+			Termination::Cancelled => Err(crate::Error::Cancelled {
+				elapsed: self.elapsed,
+				stdout: self.stdout,
+				stderr: self.stderr,
+			}),
+			Termination::Exited
+			| Termination::OutputLimitExceeded
+			| Termination::TeeFailed
+			| Termination::Signaled(_) => Ok(self),
+		}
+	}
+}
+
+impl From<CmdOutput> for Output {
+	fn from(value: CmdOutput) -> Self {
+		Output {
+			status: value.status,
+			stdout: value.stdout,
+			stderr: value.stderr,
+		}
+	}
+}
+
+impl From<CmdOutput> for CmdError {
+	fn from(value: CmdOutput) -> Self {
+		CmdError {
+			status: Some(value.status),
+			stdout: value.stdout,
+			stderr: value.stderr,
+			command: Some(value.command_line),
+			attempts: value.attempts,
+			stdout_truncated: value.stdout_truncated,
+			stderr_truncated: value.stderr_truncated,
+		}
+	}
+}
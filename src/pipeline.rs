@@ -0,0 +1,307 @@
+use std::io;
+use std::io::ErrorKind;
+use std::process::{ChildStdout, ExitStatus, Output, Stdio};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crossbeam::channel::Receiver;
+use tracing::{warn, Level, Span};
+
+use crate::debug::CommandDebug;
+use crate::impls::spawn;
+use crate::sink::{check_tee, kill_hook, Captured, OutputSink};
+use crate::span::CmdSpan;
+use crate::waiter::{or_kill_all, ChildWaiter, Supervisor};
+use crate::{Cmd, TermPolicy, TraceTarget};
+
+/// A sequence of commands, each one reading the stdout of the previous one.
+///
+/// Every stage keeps its own configuration (timeout, cancel signal, environment, ...), while the
+/// timeout and the cancel signal of the pipeline are applied to all the stages at once.
+#[derive(Debug)]
+pub struct Pipeline {
+	pub(crate) stages: Vec<Cmd>,
+	pub(crate) pipefail: bool,
+	pub(crate) debug: bool,
+	pub(crate) timeout: Option<Duration>,
+	pub(crate) signal: Option<Receiver<()>>,
+	pub(crate) termination: TermPolicy,
+	pub(crate) trace_target: TraceTarget,
+	pub(crate) trace_level: Level,
+	pub(crate) trace_label: Option<String>,
+}
+
+/// The output of a [`Pipeline`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PipelineOutput {
+	/// The status of the pipeline: the status of the last stage or, with `pipefail`,
+	/// the status of the rightmost stage which didn't succeed.
+	pub status: ExitStatus,
+	/// The status of every stage, in order.
+	pub statuses: Vec<ExitStatus>,
+	/// The stdout of the last stage.
+	pub stdout: Vec<u8>,
+	/// The stderr of the last stage.
+	pub stderr: Vec<u8>,
+	/// The stderr of every stage, in order.
+	pub stage_stderr: Vec<Vec<u8>>,
+}
+
+impl Default for Pipeline {
+	fn default() -> Self {
+		Pipeline {
+			stages: vec![],
+			pipefail: false,
+			debug: false,
+			timeout: None,
+			signal: None,
+			termination: TermPolicy::kill(),
+			trace_target: TraceTarget::Default,
+			trace_level: Level::DEBUG,
+			trace_label: None,
+		}
+	}
+}
+
+impl Pipeline {
+	pub fn new() -> Self {
+		Pipeline::default()
+	}
+
+	/// Appends a stage to the pipeline.
+	pub fn stage<T: Into<Cmd>>(mut self, cmd: T) -> Self {
+		self.stages.push(cmd.into());
+		self
+	}
+
+	/// Appends a stage to the pipeline, same as [`Pipeline::stage`].
+	pub fn pipe<T: Into<Cmd>>(self, cmd: T) -> Self {
+		self.stage(cmd)
+	}
+
+	/// When `true`, the pipeline fails if any of its stages fails, not only the last one.
+	pub fn pipefail(mut self, pipefail: bool) -> Self {
+		self.pipefail = pipefail;
+		self
+	}
+
+	pub fn with_debug(mut self, debug: bool) -> Self {
+		self.debug = debug;
+		self
+	}
+
+	pub fn with_timeout(mut self, duration: Duration) -> Self {
+		self.timeout = Some(duration);
+		self
+	}
+
+	pub fn timeout(mut self, duration: Option<Duration>) -> Self {
+		self.timeout = duration;
+		self
+	}
+
+	pub fn with_signal(mut self, signal: Receiver<()>) -> Self {
+		self.signal = Some(signal);
+		self
+	}
+
+	pub fn signal(mut self, signal: Option<Receiver<()>>) -> Self {
+		self.signal = signal;
+		self
+	}
+
+	pub fn with_termination(mut self, policy: TermPolicy) -> Self {
+		self.termination = policy;
+		self
+	}
+
+	/// Sets the target of the `tracing` span wrapping the pipeline, `simple_cmd` by default.
+	/// The stages are traced with their own target.
+	pub fn with_trace_target(mut self, target: TraceTarget) -> Self {
+		self.trace_target = target;
+		self
+	}
+
+	/// Sets the level of the `tracing` span wrapping the pipeline, `DEBUG` by default.
+	/// Every stage is traced in a child span, at the level of the stage.
+	pub fn with_trace_level(mut self, level: Level) -> Self {
+		self.trace_level = level;
+		self
+	}
+
+	pub fn with_trace_label<S: Into<String>>(mut self, label: S) -> Self {
+		self.trace_label = Some(label.into());
+		self
+	}
+
+	/// Spawns all the stages and waits for all of them to exit.
+	pub fn output(mut self) -> crate::Result<PipelineOutput> {
+		if self.stages.is_empty() {
+			return Err(io::Error::new(ErrorKind::InvalidInput, "empty pipeline").into());
+		}
+
+		let span = CmdSpan::pipeline(&self);
+		let _enter = span.span().enter();
+
+		if self.debug {
+			self.debug();
+		}
+
+		let supervisor = Supervisor {
+			cancel: self.signal.take(),
+			timeout: self.timeout.take(),
+			termination: self.termination,
+			debug: self.debug,
+			span: Span::current(),
+			cause: Default::default(),
+		};
+
+		let count = self.stages.len();
+		let mut waiters: Vec<Arc<ChildWaiter>> = Vec::with_capacity(count);
+		let mut stage_spans: Vec<CmdSpan> = Vec::with_capacity(count);
+		let mut supervisor_threads: Vec<JoinHandle<()>> = Vec::new();
+		let mut stderr_threads: Vec<Option<JoinHandle<io::Result<Captured>>>> = Vec::with_capacity(count);
+		let mut previous_stdout: Option<ChildStdout> = None;
+		let mut last_stdout: Option<(ChildStdout, OutputSink)> = None;
+
+		for (index, mut stage) in self.stages.into_iter().enumerate() {
+			let is_last = index + 1 == count;
+			let stage_span = CmdSpan::new(&stage, "pipe");
+			// the stage supervisor records its events in the span of the stage
+			let stage_supervisor = stage_span.span().in_scope(|| stage.supervisor());
+			let kill_tree = stage.kill_tree;
+			let (mut stdout_options, mut stderr_options) = stage.take_stream_options();
+
+			if let Some(stdout) = previous_stdout.take() {
+				stage.stdin = Some(Stdio::from(stdout));
+			}
+
+			if !is_last {
+				stage.stdout = Some(Stdio::piped());
+			}
+
+			let redaction = std::mem::take(&mut stage.redaction);
+			let merge_stderr = stage.merge_stderr;
+			let mut command = stage.command();
+			let spawned = spawn(&mut command, &redaction, merge_stderr);
+			drop(command);
+
+			// the stages already spawned must not be left running when one of them fails to start
+			let mut child = or_kill_all(spawned, &waiters)?;
+			let stdout = child.stdout.take();
+			let stderr = child.stderr.take();
+			stage_span.record_pid(child.id());
+			let waiter = Arc::new(or_kill_all(ChildWaiter::new(child, kill_tree), &waiters)?);
+			waiters.push(Arc::clone(&waiter));
+
+			let kill = kill_hook(&waiter, None);
+			stdout_options.kill = Some(Arc::clone(&kill));
+			stderr_options.kill = Some(kill);
+
+			if is_last {
+				last_stdout = stdout.map(|s| (s, OutputSink::new(stdout_options)));
+			} else {
+				previous_stdout = stdout;
+			}
+
+			stderr_threads.push(match stderr {
+				Some(stderr) => {
+					let spawned = std::thread::Builder::new()
+						.name("cmd_stderr".to_string())
+						.spawn(move || OutputSink::new(stderr_options).read_from(stderr));
+					Some(or_kill_all(spawned, &waiters)?)
+				}
+				None => None,
+			});
+
+			supervisor_threads.extend(or_kill_all(stage_supervisor.spawn(vec![waiter]), &waiters)?);
+			stage_spans.push(stage_span);
+		}
+
+		supervisor_threads.extend(or_kill_all(supervisor.spawn(waiters.clone()), &waiters)?);
+
+		let stdout = match last_stdout {
+			Some((stdout, sink)) => sink.read_from(stdout),
+			None => Ok(Captured::default()),
+		};
+
+		let stage_stderr = stderr_threads
+			.into_iter()
+			.map(|t| match t {
+				Some(handle) => handle
+					.join()
+					.unwrap_or_else(|_| Err(io::Error::other("stderr reader thread panicked"))),
+				None => Ok(Captured::default()),
+			})
+			.collect::<io::Result<Vec<_>>>();
+
+		for waiter in &waiters {
+			waiter.release();
+		}
+
+		let statuses = waiters.iter().map(|w| w.wait()).collect::<io::Result<Vec<_>>>();
+		if let Ok(statuses) = &statuses {
+			for (stage_span, status) in stage_spans.iter().zip(statuses) {
+				stage_span.record_exit(Some(status));
+			}
+		}
+
+		for thread in supervisor_threads {
+			if let Err(_err) = thread.join() {
+				warn!("failed to join the thread!");
+			}
+		}
+
+		let statuses = statuses?;
+		let mut stdout = stdout?;
+		let mut stage_stderr = stage_stderr?;
+		let status = pipeline_status(&statuses, self.pipefail);
+		span.record_exit(Some(&status));
+
+		let tee_failure = stdout
+			.take_tee_error("stdout")
+			.or_else(|| stage_stderr.iter_mut().find_map(|c| c.take_tee_error("stderr")));
+		let output = PipelineOutput {
+			status,
+			stderr: stage_stderr[count - 1].bytes.clone(),
+			statuses,
+			stdout: stdout.bytes,
+			stage_stderr: stage_stderr.into_iter().map(|c| c.bytes).collect(),
+		};
+		check_tee(output, tee_failure)
+	}
+}
+
+/// The status of the last stage or, with `pipefail`, the status of the rightmost stage which didn't succeed.
+pub(crate) fn pipeline_status(statuses: &[ExitStatus], pipefail: bool) -> ExitStatus {
+	let last = statuses[statuses.len() - 1];
+	if pipefail {
+		statuses.iter().rev().find(|s| !s.success()).copied().unwrap_or(last)
+	} else {
+		last
+	}
+}
+
+impl<T: Into<Cmd>> FromIterator<T> for Pipeline {
+	fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+		iter.into_iter().fold(Pipeline::new(), |p, cmd| p.stage(cmd))
+	}
+}
+
+impl PipelineOutput {
+	pub fn success(&self) -> bool {
+		self.status.success()
+	}
+
+}
+
+impl From<PipelineOutput> for Output {
+	fn from(value: PipelineOutput) -> Self {
+		Output {
+			status: value.status,
+			stdout: value.stdout,
+			stderr: value.stderr,
+		}
+	}
+}
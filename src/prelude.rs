@@ -1,49 +1,121 @@
+use std::borrow::Cow;
 #[cfg(all(not(target_os = "hermit"), any(unix, doc)))]
 use std::os::unix::prelude::ExitStatusExt;
-use std::process::Output;
+use std::process::{ExitStatus, Output};
 
-pub trait OutputExt {
-	fn success(&self) -> bool;
-	fn error(&self) -> bool;
-	fn has_stdout(&self) -> bool;
-
-	#[cfg(all(not(target_os = "hermit"), any(unix, doc)))]
-	fn has_signal(&self) -> bool;
+pub use crate::OutputResult;
+use crate::{CmdOutput, Termination, Vec8ToString};
 
-	#[cfg(all(not(target_os = "hermit"), any(unix, doc)))]
-	fn signal(&self) -> Option<i32>;
+/// Helpers on the output of a command. Only the accessors to the status and to the captured streams
+/// have to be implemented, the other methods are derived from them.
+pub trait OutputExt {
+	fn status(&self) -> &ExitStatus;
+	fn stdout(&self) -> &Vec<u8>;
+	fn stderr(&self) -> &Vec<u8>;
 
-	fn interrupt(&self) -> bool;
-	fn kill(&self) -> bool;
-}
+	/// Why the command has stopped running, `None` when it isn't known.
+	fn termination(&self) -> Option<Termination> {
+		None
+	}
 
-impl OutputExt for Output {
 	fn success(&self) -> bool {
-		self.status.success()
+		self.status().success()
 	}
+
 	fn error(&self) -> bool {
-		!self.status.success()
+		!self.status().success()
 	}
 
 	fn has_stdout(&self) -> bool {
-		!self.stdout.is_empty()
+		!self.stdout().is_empty()
 	}
 
 	#[cfg(all(not(target_os = "hermit"), any(unix, doc)))]
 	fn has_signal(&self) -> bool {
-		self.status.signal().is_some()
+		self.status().signal().is_some()
 	}
 
 	#[cfg(all(not(target_os = "hermit"), any(unix, doc)))]
 	fn signal(&self) -> Option<i32> {
-		self.status.signal()
+		self.status().signal()
 	}
 
 	fn interrupt(&self) -> bool {
 		self.signal().map(|s| signal_hook::consts::SIGINT == s).unwrap_or(false)
 	}
 
+	fn terminate(&self) -> bool {
+		self.signal().map(|s| signal_hook::consts::SIGTERM == s).unwrap_or(false)
+	}
+
 	fn kill(&self) -> bool {
 		self.signal().map(|s| signal_hook::consts::SIGKILL == s).unwrap_or(false)
 	}
+
+	/// The command was terminated because its timeout expired.
+	/// Always `false` for an [`Output`], which doesn't know why the command was terminated, see [`CmdOutput`].
+	fn timed_out(&self) -> bool {
+		self.termination() == Some(Termination::TimedOut)
+	}
+
+	/// The command was terminated because the cancel signal was received.
+	/// Always `false` for an [`Output`], which doesn't know why the command was terminated, see [`CmdOutput`].
+	fn cancelled(&self) -> bool {
+		self.termination() == Some(Termination::Cancelled)
+	}
+
+	/// See [`Vec8ToString::as_str_lossy`].
+	fn stdout_lossy(&self) -> Cow<'_, str> {
+		self.stdout().as_str_lossy()
+	}
+
+	fn stderr_lossy(&self) -> Cow<'_, str> {
+		self.stderr().as_str_lossy()
+	}
+
+	/// See [`Vec8ToString::trimmed`].
+	fn stdout_trimmed(&self) -> String {
+		self.stdout().trimmed()
+	}
+
+	/// See [`Vec8ToString::lines_lossy`].
+	fn stdout_lines(&self) -> Vec<String> {
+		self.stdout().lines_lossy()
+	}
+
+	fn stderr_lines(&self) -> Vec<String> {
+		self.stderr().lines_lossy()
+	}
+}
+
+impl OutputExt for Output {
+	fn status(&self) -> &ExitStatus {
+		&self.status
+	}
+
+	fn stdout(&self) -> &Vec<u8> {
+		&self.stdout
+	}
+
+	fn stderr(&self) -> &Vec<u8> {
+		&self.stderr
+	}
+}
+
+impl OutputExt for CmdOutput {
+	fn status(&self) -> &ExitStatus {
+		&self.status
+	}
+
+	fn stdout(&self) -> &Vec<u8> {
+		&self.stdout
+	}
+
+	fn stderr(&self) -> &Vec<u8> {
+		&self.stderr
+	}
+
+	fn termination(&self) -> Option<Termination> {
+		Some(self.termination)
+	}
 }
@@ -0,0 +1,144 @@
+use std::collections::hash_map::RandomState;
+use std::fmt::{Debug, Formatter};
+use std::hash::BuildHasher;
+use std::io;
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crossbeam::channel::{Receiver, RecvTimeoutError};
+use tracing::warn;
+
+use crate::errors::CmdError;
+use crate::{Cmd, CmdOutput, Termination};
+
+/// The delay before the next attempt of a failed command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backoff {
+	/// Always the same delay.
+	Fixed(Duration),
+	/// `initial`, doubled after every attempt, up to `max`.
+	Exponential { initial: Duration, max: Duration },
+	/// A random delay between zero and the [`Backoff::Exponential`] delay, so that the commands
+	/// failing at the same time are not retried all together.
+	Jitter { initial: Duration, max: Duration },
+}
+
+/// Describes when and how a failed command is retried by [`Cmd::output`].
+///
+/// A command has failed when its [`crate::SuccessPolicy`] doesn't accept it. It is retried as long as
+/// there are attempts left and `retry_if` returns `true` for the failure. A cancelled command is never retried.
+#[derive(Clone)]
+pub struct RetryPolicy {
+	/// The maximum number of times the command is run, the first one included.
+	pub attempts: u32,
+	pub backoff: Backoff,
+	pub retry_if: Arc<dyn Fn(&CmdError) -> bool + Send + Sync>,
+}
+
+impl Backoff {
+	/// The delay after the `attempt`-th attempt, starting from 1.
+	pub fn delay(&self, attempt: u32) -> Duration {
+		match *self {
+			Backoff::Fixed(delay) => delay,
+			Backoff::Exponential { initial, max } => exponential(initial, max, attempt),
+			Backoff::Jitter { initial, max } => {
+				let delay = exponential(initial, max, attempt);
+				let random = RandomState::new().hash_one(attempt);
+				delay.mul_f64((random % 1000) as f64 / 1000.0)
+			}
+		}
+	}
+}
+
+fn exponential(initial: Duration, max: Duration, attempt: u32) -> Duration {
+	let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+	initial.saturating_mul(factor).min(max)
+}
+
+impl RetryPolicy {
+	/// Retries every failure.
+	pub fn new(attempts: u32, backoff: Backoff) -> Self {
+		RetryPolicy {
+			attempts,
+			backoff,
+			retry_if: Arc::new(|_| true),
+		}
+	}
+
+	/// Retries only the failures for which `predicate` returns `true`.
+	pub fn retry_if<F>(mut self, predicate: F) -> Self
+	where
+		F: Fn(&CmdError) -> bool + Send + Sync + 'static,
+	{
+		self.retry_if = Arc::new(predicate);
+		self
+	}
+}
+
+impl Debug for RetryPolicy {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("RetryPolicy")
+			.field("attempts", &self.attempts)
+			.field("backoff", &self.backoff)
+			.finish()
+	}
+}
+
+impl Cmd {
+	/// Runs the command until it succeeds or the policy gives up, returning the output of the last attempt.
+	///
+	/// Every attempt is run with the whole timeout, and is spawned from the same [`std::process::Command`] so that
+	/// it gets the same stdio, see [`crate::CommandBuilder::with_retry`].
+	pub(crate) fn output_with_retry(&mut self, policy: &RetryPolicy) -> crate::Result<CmdOutput> {
+		if self.fd_stdio {
+			let message = "a file or a pipe can't be the stdio of a retried command";
+			return Err(io::Error::new(ErrorKind::InvalidInput, message).into());
+		}
+
+		let mut command = self.build_command();
+		let mut attempt = 1;
+
+		loop {
+			let mut output = self.output_of(&mut command)?;
+			output.attempts = attempt;
+
+			let succeeded = self.success.accepts(&output.status, &output.stderr);
+			if succeeded || output.termination == Termination::Cancelled || attempt >= policy.attempts {
+				return Ok(output);
+			}
+
+			if !(policy.retry_if)(&CmdError::from(output.clone())) {
+				return Ok(output);
+			}
+
+			let delay = policy.backoff.delay(attempt);
+			if self.debug {
+				warn!("attempt {attempt} failed, retrying in {delay:?}...");
+			}
+
+			if wait_cancel(self.signal.as_ref(), delay) {
+				if self.debug {
+					warn!("ctrl+c received");
+				}
+				return Ok(output);
+			}
+
+			attempt += 1;
+		}
+	}
+}
+
+/// Sleeps for `delay`, returning `true` as soon as the cancel signal is received.
+fn wait_cancel(cancel: Option<&Receiver<()>>, delay: Duration) -> bool {
+	let deadline = Instant::now() + delay;
+	match cancel.map(|cancel| cancel.recv_deadline(deadline)) {
+		Some(Ok(())) => true,
+		Some(Err(RecvTimeoutError::Timeout)) => false,
+		// nobody can cancel the command anymore
+		Some(Err(RecvTimeoutError::Disconnected)) | None => {
+			std::thread::sleep(deadline.saturating_duration_since(Instant::now()));
+			false
+		}
+	}
+}
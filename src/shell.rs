@@ -0,0 +1,171 @@
+use std::borrow::Cow;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+
+/// Splits `line` into words following the POSIX shell quoting rules: single quotes, double quotes
+/// and backslash escapes. Nothing is expanded: variables, globs and operators are kept as they are.
+pub(crate) fn split(line: &str) -> crate::Result<Vec<String>> {
+	let error = |reason: String| crate::Error::Parse {
+		input: line.to_string(),
+		reason,
+	};
+
+	let mut words = vec![];
+	let mut word = String::new();
+	// an empty quoted string is still a word
+	let mut in_word = false;
+	let mut chars = line.char_indices();
+
+	while let Some((offset, c)) = chars.next() {
+		match c {
+			' ' | '\t' | '\n' => {
+				if in_word {
+					words.push(std::mem::take(&mut word));
+					in_word = false;
+				}
+			}
+			'\'' => {
+				in_word = true;
+				loop {
+					match chars.next() {
+						Some((_, '\'')) => break,
+						Some((_, c)) => word.push(c),
+						None => return Err(error(format!("unterminated single quote at offset {offset}"))),
+					}
+				}
+			}
+			'"' => {
+				in_word = true;
+				loop {
+					match chars.next() {
+						Some((_, '"')) => break,
+						Some((_, '\\')) => match chars.next() {
+							Some((_, c @ ('$' | '`' | '"' | '\\'))) => word.push(c),
+							Some((_, '\n')) => {}
+							Some((_, c)) => {
+								word.push('\\');
+								word.push(c);
+							}
+							None => return Err(error(format!("unterminated double quote at offset {offset}"))),
+						},
+						Some((_, c)) => word.push(c),
+						None => return Err(error(format!("unterminated double quote at offset {offset}"))),
+					}
+				}
+			}
+			'\\' => match chars.next() {
+				Some((_, '\n')) => {}
+				Some((_, c)) => {
+					in_word = true;
+					word.push(c);
+				}
+				None => return Err(error(format!("trailing backslash at offset {offset}"))),
+			},
+			c => {
+				in_word = true;
+				word.push(c);
+			}
+		}
+	}
+
+	if in_word {
+		words.push(word);
+	}
+
+	if words.is_empty() {
+		return Err(error("empty command line".to_string()));
+	}
+	Ok(words)
+}
+
+/// Quotes `word` so that [`split`] (or a POSIX shell) reads it back as a single word.
+/// Words made only of safe characters are returned as they are, words which aren't valid UTF-8
+/// are rendered with the bash `$'...'` quoting, escaping the invalid bytes.
+pub(crate) fn quote(word: &OsStr) -> Cow<'_, str> {
+	let is_safe = |c: char| c.is_ascii_alphanumeric() || "_-./=:,+@%^".contains(c);
+
+	match word.to_str() {
+		Some(word) if !word.is_empty() && word.chars().all(is_safe) => Cow::Borrowed(word),
+		Some(word) => Cow::Owned(format!("'{}'", word.replace('\'', r"'\''"))),
+		None => Cow::Owned(ansi_c_quote(word.as_bytes())),
+	}
+}
+
+fn ansi_c_quote(bytes: &[u8]) -> String {
+	let mut quoted = String::from("$'");
+	for chunk in bytes.utf8_chunks() {
+		for c in chunk.valid().chars() {
+			match c {
+				'\\' | '\'' => {
+					quoted.push('\\');
+					quoted.push(c);
+				}
+				c if c.is_ascii_control() => quoted.push_str(&format!("\\x{:02x}", c as u8)),
+				c => quoted.push(c),
+			}
+		}
+		for byte in chunk.invalid() {
+			quoted.push_str(&format!("\\x{byte:02x}"));
+		}
+	}
+	quoted.push('\'');
+	quoted
+}
+
+/// Quotes every word and joins them with spaces.
+pub(crate) fn join<'a, I: IntoIterator<Item = &'a OsStr>>(words: I) -> String {
+	words.into_iter().map(quote).collect::<Vec<_>>().join(" ")
+}
+
+/// Renders a command line which can be pasted into bash: the working directory and the environment
+/// changes are rendered as a prefix, e.g. `cd dir && FOO=bar prog args`.
+pub(crate) fn command_line<'a>(
+	program: &'a OsStr,
+	args: impl IntoIterator<Item = &'a OsStr>,
+	cwd: Option<&OsStr>,
+	envs: impl IntoIterator<Item = (&'a OsStr, Option<&'a OsStr>)>,
+	env_clear: bool,
+	env_inherit: Option<&[OsString]>,
+) -> String {
+	let mut line = String::new();
+	if let Some(cwd) = cwd {
+		line.push_str(&format!("cd {} && ", quote(cwd)));
+	}
+
+	let mut assignments = vec![];
+	let mut env_options = vec![];
+	if env_clear || env_inherit.is_some() {
+		env_options.push("-i".to_string());
+	}
+	// the inherited variables are expanded by the shell the command line is pasted into
+	for key in env_inherit.unwrap_or_default() {
+		assignments.push(format!("{}=\"${}\"", key.to_string_lossy(), key.to_string_lossy()));
+	}
+	// like `Command`, only the last change of every variable is applied
+	let mut changes: Vec<(&OsStr, Option<&OsStr>)> = vec![];
+	for (key, value) in envs {
+		changes.retain(|(k, _)| *k != key);
+		changes.push((key, value));
+	}
+	for (key, value) in changes {
+		match value {
+			Some(value) => assignments.push(format!("{}={}", key.to_string_lossy(), quote(value))),
+			None => env_options.push(format!("-u {}", quote(key))),
+		}
+	}
+
+	if !env_options.is_empty() {
+		line.push_str("env ");
+		for option in env_options {
+			line.push_str(&option);
+			line.push(' ');
+		}
+	}
+	for assignment in assignments {
+		line.push_str(&assignment);
+		line.push(' ');
+	}
+
+	line.push_str(&join(std::iter::once(program).chain(args)));
+	line
+}
@@ -0,0 +1,311 @@
+use std::collections::VecDeque;
+use std::fmt::{Debug, Formatter};
+use std::io;
+use std::io::{Read, Write};
+use std::process::Output;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use crate::impls::LinesVec;
+use crate::waiter::ChildWaiter;
+use crate::Termination;
+
+/// Shared, so that the same callbacks are invoked again when a command is retried.
+pub(crate) type OutputCallback = Arc<Mutex<dyn FnMut(&[u8]) + Send>>;
+
+pub(crate) fn callback<F: FnMut(&[u8]) + Send + 'static>(callback: F) -> OutputCallback {
+	Arc::new(Mutex::new(callback))
+}
+
+/// A writer receiving a copy of the output, shared like the callbacks.
+pub(crate) type TeeWriter = Arc<Mutex<dyn Write + Send>>;
+
+pub(crate) fn invoke(callback: &OutputCallback, bytes: &[u8]) {
+	// a callback which has panicked is not invoked anymore
+	if let Ok(mut callback) = callback.lock() {
+		callback(bytes);
+	}
+}
+
+/// Lines longer than this are passed to the line callbacks in pieces, so that a stream without newlines
+/// can't grow the line buffer without bounds. The default of [`crate::CommandBuilder::max_line_bytes`].
+pub(crate) const MAX_LINE_BYTES: usize = 1024 * 1024;
+
+/// What is done when a command writes more than the maximum number of bytes to stdout or stderr.
+/// See [`crate::CommandBuilder::max_stdout_bytes`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+	/// Keeps the first bytes of the output.
+	KeepHead,
+	/// Keeps the last bytes of the output.
+	KeepTail,
+	/// Keeps the first half and the last half of the bytes.
+	KeepBoth,
+	/// Keeps the first bytes of the output and kills the command.
+	Kill,
+}
+
+/// How the output of a stream of the child process is handled while it is being read.
+#[derive(Clone)]
+pub(crate) struct StreamOptions {
+	pub(crate) on_line: Option<OutputCallback>,
+	pub(crate) on_chunk: Option<OutputCallback>,
+	pub(crate) capture: bool,
+	/// Lines longer than this are passed to `on_line` in pieces.
+	pub(crate) max_line: usize,
+	pub(crate) limit: Option<(usize, Overflow)>,
+	pub(crate) tee: Option<TeeWriter>,
+	pub(crate) kill_on_tee_error: bool,
+	/// Invoked once, with the reason, when the limit is exceeded with [`Overflow::Kill`]
+	/// or when writing to the tee fails with `kill_on_tee_error`.
+	pub(crate) kill: Option<KillHook>,
+}
+
+pub(crate) type KillHook = Arc<dyn Fn(Termination) + Send + Sync>;
+
+impl Default for StreamOptions {
+	fn default() -> Self {
+		StreamOptions {
+			on_line: None,
+			on_chunk: None,
+			capture: true,
+			max_line: MAX_LINE_BYTES,
+			limit: None,
+			tee: None,
+			kill_on_tee_error: false,
+			kill: None,
+		}
+	}
+}
+
+impl Debug for StreamOptions {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("StreamOptions")
+			.field("on_line", &self.on_line.is_some())
+			.field("on_chunk", &self.on_chunk.is_some())
+			.field("capture", &self.capture)
+			.field("max_line", &self.max_line)
+			.field("limit", &self.limit)
+			.field("tee", &self.tee.is_some())
+			.field("kill_on_tee_error", &self.kill_on_tee_error)
+			.finish()
+	}
+}
+
+/// Returns a hook for [`StreamOptions::kill`] killing the child, and recording why in `cause`.
+pub(crate) fn kill_hook(waiter: &Arc<ChildWaiter>, cause: Option<Arc<OnceLock<Termination>>>) -> KillHook {
+	let waiter = Arc::clone(waiter);
+	Arc::new(move |termination| {
+		if let Some(cause) = &cause {
+			let _ = cause.set(termination);
+		}
+		let _ = waiter.signal(signal_hook::consts::SIGKILL);
+	})
+}
+
+/// The output captured from a stream.
+#[derive(Debug, Default)]
+pub(crate) struct Captured {
+	pub(crate) bytes: Vec<u8>,
+	/// Part of the output was dropped because it exceeded the limit.
+	pub(crate) truncated: bool,
+	/// The first error returned by the tee, which hasn't received the rest of the output.
+	pub(crate) tee_error: Option<io::Error>,
+}
+
+/// The name of a stream whose tee has failed, along with the error.
+pub(crate) type TeeFailure = (&'static str, io::Error);
+
+impl Captured {
+	/// Takes the error of the tee of `stream`, if any.
+	pub(crate) fn take_tee_error(&mut self, stream: &'static str) -> Option<TeeFailure> {
+		self.tee_error.take().map(|err| (stream, err))
+	}
+}
+
+/// Takes the first error of the tees of stdout and stderr.
+pub(crate) fn tee_failure(stdout: &mut Captured, stderr: &mut Captured) -> Option<TeeFailure> {
+	stdout.take_tee_error("stdout").or_else(|| stderr.take_tee_error("stderr"))
+}
+
+/// Turns the output of a command or a pipeline whose tee has failed into an error, keeping the output.
+pub(crate) fn check_tee<T: Into<Output>>(output: T, failure: Option<TeeFailure>) -> crate::Result<T> {
+	let Some((stream, source)) = failure else {
+		return Ok(output);
+	};
+
+	let Output { status, stdout, stderr } = output.into();
+	Err(crate::Error::Tee {
+		stream,
+		source,
+		status,
+		stdout,
+		stderr,
+	})
+}
+
+/// Receives the output of a stream chunk by chunk, as it's read from the pipe,
+/// invoking the callbacks and accumulating the captured output.
+pub(crate) struct OutputSink {
+	options: StreamOptions,
+	line: Vec<u8>,
+	output: Vec<u8>,
+	// the last bytes of the output, when it has exceeded the limit and the tail is kept
+	tail: VecDeque<u8>,
+	skipped: usize,
+	tee_error: Option<io::Error>,
+}
+
+impl OutputSink {
+	pub(crate) fn new(options: StreamOptions) -> Self {
+		OutputSink {
+			options,
+			line: Vec::new(),
+			output: Vec::new(),
+			tail: VecDeque::new(),
+			skipped: 0,
+			tee_error: None,
+		}
+	}
+
+	pub(crate) fn push(&mut self, chunk: &[u8]) {
+		self.tee(chunk);
+
+		if let Some(on_chunk) = &self.options.on_chunk {
+			invoke(on_chunk, chunk);
+		}
+
+		if let Some(on_line) = &self.options.on_line {
+			for line in LinesVec::new(chunk) {
+				// reading from a slice can't fail
+				let line = line.unwrap_or_default();
+				let mut rest = &line[..];
+				while !rest.is_empty() {
+					// never more than the cap in the buffer, whatever the size of the chunks
+					let max_line = self.options.max_line;
+					let (piece, next) = rest.split_at(rest.len().min(max_line - self.line.len()));
+					self.line.extend(piece);
+					rest = next;
+					if self.line.ends_with(b"\n") {
+						invoke(on_line, &self.line[..self.line.len() - 1]);
+						self.line.clear();
+					} else if self.line.len() >= max_line {
+						invoke(on_line, &self.line);
+						self.line.clear();
+					}
+				}
+			}
+		}
+
+		if self.options.capture {
+			self.capture(chunk);
+		}
+	}
+
+	/// Copies the chunk to the tee, until it fails.
+	fn tee(&mut self, chunk: &[u8]) {
+		let Some(tee) = &self.options.tee else {
+			return;
+		};
+		if self.tee_error.is_some() {
+			return;
+		}
+
+		let result = match tee.lock() {
+			Ok(mut tee) => tee.write_all(chunk),
+			Err(_) => Err(io::Error::other("the tee has panicked")),
+		};
+		if let Err(err) = result {
+			self.tee_error = Some(err);
+			if self.options.kill_on_tee_error {
+				if let Some(kill) = &self.options.kill {
+					kill(Termination::TeeFailed);
+				}
+			}
+		}
+	}
+
+	fn capture(&mut self, chunk: &[u8]) {
+		let Some((max, overflow)) = self.options.limit else {
+			self.output.extend(chunk);
+			return;
+		};
+
+		let head_max = match overflow {
+			Overflow::KeepHead | Overflow::Kill => max,
+			Overflow::KeepTail => 0,
+			Overflow::KeepBoth => max / 2,
+		};
+		let tail_max = max - head_max;
+
+		let head = head_max.saturating_sub(self.output.len()).min(chunk.len());
+		self.output.extend(&chunk[..head]);
+
+		let rest = &chunk[head..];
+		if rest.is_empty() {
+			return;
+		}
+
+		let overflowed = self.skipped > 0;
+		self.tail.extend(rest);
+		if self.tail.len() > tail_max {
+			let excess = self.tail.len() - tail_max;
+			self.tail.drain(..excess);
+			self.skipped += excess;
+		}
+
+		if !overflowed && self.skipped > 0 && overflow == Overflow::Kill {
+			if let Some(kill) = &self.options.kill {
+				kill(Termination::OutputLimitExceeded);
+			}
+		}
+	}
+
+	/// Flushes the last line, if it wasn't terminated by a newline, and the tee,
+	/// then returns the captured output.
+	pub(crate) fn finish(mut self) -> Captured {
+		if let (Some(tee), None) = (&self.options.tee, &self.tee_error) {
+			if let Err(err) = tee.lock().map_or(Ok(()), |mut tee| tee.flush()) {
+				self.tee_error = Some(err);
+			}
+		}
+
+		if let Some(on_line) = &self.options.on_line {
+			if !self.line.is_empty() {
+				invoke(on_line, &self.line);
+			}
+		}
+
+		if self.skipped == 0 {
+			let mut bytes = self.output;
+			bytes.extend(self.tail);
+			return Captured {
+				bytes,
+				truncated: false,
+				tee_error: self.tee_error,
+			};
+		}
+
+		let mut bytes = self.output;
+		bytes.extend(format!("\n[... {} bytes truncated ...]\n", self.skipped).as_bytes());
+		bytes.extend(self.tail);
+		Captured {
+			bytes,
+			truncated: true,
+			tee_error: self.tee_error,
+		}
+	}
+
+	/// Reads the stream to the end, pushing every chunk into the sink.
+	pub(crate) fn read_from<R: Read>(mut self, mut stream: R) -> io::Result<Captured> {
+		let mut buffer = [0u8; 8192];
+		loop {
+			match stream.read(&mut buffer) {
+				Ok(0) => break,
+				Ok(n) => self.push(&buffer[..n]),
+				Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+				Err(err) => return Err(err),
+			}
+		}
+		Ok(self.finish())
+	}
+}
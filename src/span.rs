@@ -0,0 +1,125 @@
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use std::time::{Duration, Instant};
+
+use tracing::field::Empty;
+use tracing::{Level, Span};
+
+use crate::shell;
+use crate::{Cmd, Pipeline};
+
+/// The target of the `tracing` spans of a command, see [`crate::CommandBuilder::with_trace_target`].
+///
+/// The target of a span must be known at compile time, so it's one of a fixed set, all of them
+/// under `simple_cmd` so that they can be filtered together.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TraceTarget {
+	/// `simple_cmd`.
+	#[default]
+	Default,
+	/// `simple_cmd::audit`, for the commands whose executions must be recorded.
+	Audit,
+	/// `simple_cmd::metrics`, for the commands whose timings are collected.
+	Metrics,
+	/// `simple_cmd::debug`, for the noisy commands which are only traced while debugging.
+	Debug,
+}
+
+impl TraceTarget {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			TraceTarget::Default => "simple_cmd",
+			TraceTarget::Audit => "simple_cmd::audit",
+			TraceTarget::Metrics => "simple_cmd::metrics",
+			TraceTarget::Debug => "simple_cmd::debug",
+		}
+	}
+}
+
+/// Creates a span with a target and a level chosen at runtime: the target and the level of the
+/// `tracing` macros must be constants.
+macro_rules! span_at {
+	(@level $target:literal, $level:expr, $name:literal, $($fields:tt)*) => {
+		match $level {
+			Level::TRACE => tracing::span!(target: $target, Level::TRACE, $name, $($fields)*),
+			Level::DEBUG => tracing::span!(target: $target, Level::DEBUG, $name, $($fields)*),
+			Level::INFO => tracing::span!(target: $target, Level::INFO, $name, $($fields)*),
+			Level::WARN => tracing::span!(target: $target, Level::WARN, $name, $($fields)*),
+			Level::ERROR => tracing::span!(target: $target, Level::ERROR, $name, $($fields)*),
+		}
+	};
+	($target:expr, $level:expr, $name:literal, $($fields:tt)*) => {
+		match $target {
+			TraceTarget::Default => span_at!(@level "simple_cmd", $level, $name, $($fields)*),
+			TraceTarget::Audit => span_at!(@level "simple_cmd::audit", $level, $name, $($fields)*),
+			TraceTarget::Metrics => span_at!(@level "simple_cmd::metrics", $level, $name, $($fields)*),
+			TraceTarget::Debug => span_at!(@level "simple_cmd::debug", $level, $name, $($fields)*),
+		}
+	};
+}
+
+/// The span of a command execution. The pid, the duration and the exit status are recorded
+/// as they become known; durations are in seconds.
+pub(crate) struct CmdSpan {
+	span: Span,
+	start: Instant,
+}
+
+impl CmdSpan {
+	pub(crate) fn new(cmd: &Cmd, operation: &'static str) -> Self {
+		let span = span_at!(
+			cmd.trace_target,
+			cmd.trace_level,
+			"cmd",
+			operation,
+			label = cmd.trace_label.as_deref(),
+			program = %cmd.program.to_string_lossy(),
+			args = %shell::join(cmd.redaction.args(&cmd.args)),
+			cwd = cmd.cwd.as_ref().map(|cwd| cwd.to_string_lossy()).as_deref(),
+			timeout = cmd.timeout.as_ref().map(Duration::as_secs_f64),
+			pid = Empty,
+			duration = Empty,
+			exit_code = Empty,
+			signal = Empty,
+		);
+		CmdSpan {
+			span,
+			start: Instant::now(),
+		}
+	}
+
+	pub(crate) fn pipeline(pipeline: &Pipeline) -> Self {
+		let span = span_at!(
+			pipeline.trace_target,
+			pipeline.trace_level,
+			"cmd_pipeline",
+			label = pipeline.trace_label.as_deref(),
+			stages = pipeline.stages.len(),
+			timeout = pipeline.timeout.as_ref().map(Duration::as_secs_f64),
+			duration = Empty,
+			exit_code = Empty,
+			signal = Empty,
+		);
+		CmdSpan {
+			span,
+			start: Instant::now(),
+		}
+	}
+
+	pub(crate) fn span(&self) -> &Span {
+		&self.span
+	}
+
+	pub(crate) fn record_pid(&self, pid: u32) {
+		self.span.record("pid", pid);
+	}
+
+	/// Records the duration since the span was created, and the exit status if the command has exited.
+	pub(crate) fn record_exit(&self, status: Option<&ExitStatus>) {
+		self.span.record("duration", self.start.elapsed().as_secs_f64());
+		if let Some(status) = status {
+			self.span.record("exit_code", status.code());
+			self.span.record("signal", status.signal());
+		}
+	}
+}
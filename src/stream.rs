@@ -0,0 +1,156 @@
+use std::io;
+use std::process::ExitStatus;
+use std::sync::Arc;
+
+use crossbeam::channel::Receiver;
+use crossbeam_channel::{unbounded, Sender};
+use tracing::warn;
+
+use crate::debug::CommandDebug;
+use crate::impls::spawn;
+use crate::sink;
+use crate::sink::{Captured, OutputSink, StreamOptions};
+use crate::waiter::{or_kill_all, ChildWaiter};
+use crate::Cmd;
+
+/// An event produced by a running command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+	/// A line written to stdout, without the trailing newline. A line longer than
+	/// [`crate::CommandBuilder::max_line_bytes`] is split into several events.
+	Stdout(Vec<u8>),
+	/// A line written to stderr, without the trailing newline. Split like [`Event::Stdout`].
+	Stderr(Vec<u8>),
+	/// The command has exited. This is always the last event.
+	Exit(ExitStatus),
+}
+
+/// Iterator over the [`Event`]s of a command spawned with [`Cmd::stream`].
+///
+/// Unless [`crate::CommandBuilder::kill_on_drop`] is set to `false`, the command is killed
+/// if the iterator is dropped before the command has exited.
+#[derive(Debug)]
+pub struct CmdStream {
+	receiver: Receiver<io::Result<Event>>,
+	waiter: Arc<ChildWaiter>,
+	finished: bool,
+	kill_on_drop: bool,
+}
+
+impl Cmd {
+	/// Spawns the command and returns an iterator over its output, line by line, as it's produced.
+	/// The timeout and the cancel signal are enforced as with [`Cmd::output`].
+	pub fn stream(mut self) -> crate::Result<CmdStream> {
+		if self.debug {
+			self.debug();
+		}
+
+		let supervisor = self.supervisor();
+		let kill_tree = self.kill_tree;
+		let kill_on_drop = self.kill_on_drop;
+		let (stdout_options, stderr_options) = self.take_stream_options();
+
+		let redaction = std::mem::take(&mut self.redaction);
+		let merge_stderr = self.merge_stderr;
+		let mut command = self.command();
+		let mut child = spawn(&mut command, &redaction, merge_stderr)?;
+		drop(command);
+
+		let stdout = child.stdout.take();
+		let stderr = child.stderr.take();
+
+		let waiter = Arc::new(ChildWaiter::new(child, kill_tree)?);
+		let waiters = std::slice::from_ref(&waiter);
+		let supervisor_thread = or_kill_all(supervisor.spawn(vec![Arc::clone(&waiter)]), waiters)?;
+
+		let (sender, receiver) = unbounded();
+
+		let stderr_sender = sender.clone();
+		let stderr_thread = std::thread::Builder::new().name("cmd_stderr".to_string()).spawn(move || {
+			if let Some(stderr) = stderr {
+				let options = forward_lines(stderr_options, stderr_sender.clone(), Event::Stderr);
+				// a failed tee is reported as a read error
+				if let Ok(Captured { tee_error: Some(err), .. }) | Err(err) = OutputSink::new(options).read_from(stderr) {
+					let _ = stderr_sender.send(Err(err));
+				}
+			}
+		});
+		let stderr_thread = or_kill_all(stderr_thread, waiters)?;
+
+		let stdout_waiter = Arc::clone(&waiter);
+		let spawned = std::thread::Builder::new().name("cmd_stream".to_string()).spawn(move || {
+			if let Some(stdout) = stdout {
+				let options = forward_lines(stdout_options, sender.clone(), Event::Stdout);
+				// a failed tee is reported as a read error
+				if let Ok(Captured { tee_error: Some(err), .. }) | Err(err) = OutputSink::new(options).read_from(stdout) {
+					let _ = sender.send(Err(err));
+				}
+			}
+
+			let _ = stderr_thread.join();
+			stdout_waiter.release();
+			let _ = sender.send(stdout_waiter.wait().map(Event::Exit));
+
+			if let Some(Err(_err)) = supervisor_thread.map(|t| t.join()) {
+				warn!("failed to join the thread!");
+			}
+		});
+		or_kill_all(spawned, waiters)?;
+
+		Ok(CmdStream {
+			receiver,
+			waiter,
+			finished: false,
+			kill_on_drop,
+		})
+	}
+}
+
+/// Sends every line to the stream, after the callback configured on the builder, if any.
+fn forward_lines(
+	mut options: StreamOptions,
+	sender: Sender<io::Result<Event>>,
+	event: fn(Vec<u8>) -> Event,
+) -> StreamOptions {
+	let on_line = options.on_line.take();
+	options.on_line = Some(sink::callback(move |line: &[u8]| {
+		if let Some(on_line) = &on_line {
+			sink::invoke(on_line, line);
+		}
+		let _ = sender.send(Ok(event(line.to_vec())));
+	}));
+	options.capture = false;
+	options
+}
+
+impl CmdStream {
+	/// Returns the pid of the command.
+	pub fn id(&self) -> u32 {
+		self.waiter.id()
+	}
+}
+
+impl Iterator for CmdStream {
+	type Item = io::Result<Event>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		if self.finished {
+			return None;
+		}
+
+		let item = self.receiver.recv().ok();
+		if matches!(item, None | Some(Ok(Event::Exit(_)))) {
+			self.finished = true;
+		}
+		item
+	}
+}
+
+impl Drop for CmdStream {
+	fn drop(&mut self) {
+		if self.kill_on_drop && !self.finished {
+			let _ = self.waiter.signal(signal_hook::consts::SIGKILL);
+		}
+		self.waiter.release();
+	}
+}
@@ -0,0 +1,120 @@
+use std::fmt::{Debug, Formatter};
+use std::process::ExitStatus;
+use std::sync::Arc;
+
+use crate::errors::CmdError;
+use crate::prelude::OutputExt;
+use crate::{Cmd, OutputResult};
+
+/// Decides whether a command has succeeded, from its exit code and its stderr.
+///
+/// By default only the exit code `0` is a success, whatever the command writes to stderr.
+/// A command terminated by a signal has always failed.
+#[derive(Clone)]
+pub struct SuccessPolicy {
+	/// The exit codes of a successful command.
+	pub exit_codes: Vec<i32>,
+	/// When `true`, a command writing anything to stderr has failed.
+	pub stderr_is_error: bool,
+	/// A command has failed when this returns `true` for its stderr, decoded lossily.
+	pub fail_if_stderr: Option<StderrPredicate>,
+}
+
+pub type StderrPredicate = Arc<dyn Fn(&str) -> bool + Send + Sync>;
+
+impl Default for SuccessPolicy {
+	fn default() -> Self {
+		SuccessPolicy {
+			exit_codes: vec![0],
+			stderr_is_error: false,
+			fail_if_stderr: None,
+		}
+	}
+}
+
+impl SuccessPolicy {
+	pub fn new() -> Self {
+		SuccessPolicy::default()
+	}
+
+	/// The exit code must be `0` and the stderr must be empty.
+	pub fn strict() -> Self {
+		SuccessPolicy::new().stderr_is_error(true)
+	}
+
+	pub fn exit_codes<I: IntoIterator<Item = i32>>(mut self, codes: I) -> Self {
+		self.exit_codes = codes.into_iter().collect();
+		self
+	}
+
+	pub fn stderr_is_error(mut self, value: bool) -> Self {
+		self.stderr_is_error = value;
+		self
+	}
+
+	/// Fails the commands for which `predicate` returns `true` on their stderr, even if they
+	/// exited with an accepted code.
+	pub fn fail_if_stderr<F>(mut self, predicate: F) -> Self
+	where
+		F: Fn(&str) -> bool + Send + Sync + 'static,
+	{
+		self.fail_if_stderr = Some(Arc::new(predicate));
+		self
+	}
+
+	/// Fails the commands whose stderr contains `pattern`.
+	pub fn fail_if_stderr_contains<S: Into<String>>(self, pattern: S) -> Self {
+		let pattern = pattern.into();
+		self.fail_if_stderr(move |stderr| stderr.contains(&pattern))
+	}
+
+	/// Fails the commands whose stderr matches `regex`.
+	#[cfg(feature = "regex")]
+	pub fn fail_if_stderr_matches(self, regex: regex::Regex) -> Self {
+		self.fail_if_stderr(move |stderr| regex.is_match(stderr))
+	}
+
+	pub fn accepts(&self, status: &ExitStatus, stderr: &[u8]) -> bool {
+		let accepted = status.code().is_some_and(|code| self.exit_codes.contains(&code));
+		if !accepted || (self.stderr_is_error && !stderr.is_empty()) {
+			return false;
+		}
+
+		match &self.fail_if_stderr {
+			Some(predicate) => !predicate(&String::from_utf8_lossy(stderr)),
+			None => true,
+		}
+	}
+}
+
+impl Debug for SuccessPolicy {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("SuccessPolicy")
+			.field("exit_codes", &self.exit_codes)
+			.field("stderr_is_error", &self.stderr_is_error)
+			.field("fail_if_stderr", &self.fail_if_stderr.is_some())
+			.finish()
+	}
+}
+
+/// The stdout is taken back from the [`CmdError`] the output turns into, whether the command has failed or not.
+impl<T: OutputExt + Into<CmdError>> OutputResult for T {
+	fn into_result(self, policy: &SuccessPolicy) -> crate::Result<Vec<u8>> {
+		let accepted = policy.accepts(self.status(), self.stderr());
+		let output: CmdError = self.into();
+		if accepted {
+			Ok(output.stdout)
+		} else {
+			Err(crate::Error::CommandError(output))
+		}
+	}
+}
+
+impl Cmd {
+	/// Runs the command with [`Cmd::output`] and returns its stdout, or an error if it has failed
+	/// according to its [`SuccessPolicy`].
+	pub fn output_checked(self) -> crate::Result<Vec<u8>> {
+		let policy = self.success.clone();
+		self.output()?.into_result(&policy)
+	}
+}
@@ -1,20 +1,29 @@
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
     use std::convert::Infallible;
-    use std::io::BufRead;
+    use std::io::{BufRead, Read, Write};
     use std::path::Path;
-    use std::process::{Command, Stdio};
-    use std::sync::Once;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::{Command, Output, Stdio};
+    use std::sync::{Arc, Mutex, Once};
     use std::thread;
     use std::thread::sleep;
-    use std::time::{Duration, Instant};
+    use std::time::{Duration, Instant, SystemTime};
 
     use crossbeam_channel::{bounded, Receiver};
     use tracing::trace;
+    use tracing_subscriber::layer::{Context, SubscriberExt};
+    use tracing_subscriber::Layer;
 
-    use crate::{Cmd, Vec8ToString};
+    use crate::errors::CmdError;
+    use crate::{
+        Backoff, Cmd, CommandBuilder, Error, Overflow, Pipeline, RetryPolicy, SuccessPolicy, TermPolicy, Termination,
+        TraceTarget, Vec8ToString,
+    };
     use crate::debug::CommandDebug;
-    use crate::prelude::OutputExt;
+    use crate::prelude::{OutputExt, OutputResult};
+    use crate::stream::Event;
 
     static INIT: Once = Once::new();
 
@@ -79,7 +88,7 @@ mod tests {
         assert_eq!(Some(Path::new("/Users/alessandro/Documents/Projects")), cmd.get_current_dir());
 
         let output = cmd.build().output().expect("failed to run command");
-        for line in output.stdout.lines().into_iter() {
+        for line in output.stdout.lines() {
             println!("{}", line.unwrap());
         }
     }
@@ -117,6 +126,8 @@ mod tests {
         assert!(!output.status.success());
         assert!(!output.interrupt());
         assert!(output.kill());
+        assert!(output.timed_out());
+        assert!(!output.cancelled());
     }
 
     #[test]
@@ -172,6 +183,485 @@ mod tests {
         assert!(!output.status.success());
         assert!(output.kill());
         assert!(!output.interrupt());
+        assert!(output.cancelled());
+        assert!(!output.timed_out());
+    }
+
+    #[test]
+    fn test_error_on_termination() {
+        init_log!();
+        let err = Cmd::builder("sh")
+            .args(["-c", "echo started; echo warming up >&2; sleep 10"])
+            .with_timeout(Duration::from_millis(300))
+            .kill_tree(true)
+            .error_on_termination(true)
+            .build()
+            .output()
+            .unwrap_err();
+        assert!(err.is_timeout());
+        match err {
+            Error::Timeout { elapsed, stdout, stderr } => {
+                assert!(elapsed >= Duration::from_millis(300));
+                assert_eq!("started\n", stdout.as_str().unwrap());
+                assert_eq!("warming up\n", stderr.as_str().unwrap());
+            }
+            _ => unreachable!(),
+        }
+
+        let err = Cmd::builder("sleep")
+            .arg("10")
+            .with_signal(cancel_signal(Duration::from_millis(100)).unwrap())
+            .error_on_termination(true)
+            .build()
+            .output()
+            .unwrap_err();
+        assert!(err.is_cancelled());
+
+        // a command killed by someone else is not an error
+        let output = Cmd::builder("sh")
+            .args(["-c", "kill -9 $$"])
+            .with_timeout(Duration::from_secs(5))
+            .error_on_termination(true)
+            .build()
+            .output()
+            .unwrap();
+        assert!(output.kill());
+    }
+
+    #[test]
+    fn test_retry() {
+        init_log!();
+        let counter = std::env::temp_dir().join(format!("simple-cmd-retry-{}", std::process::id()));
+        let _ = std::fs::remove_file(&counter);
+        let script = r#"n=$(cat "$1" 2>/dev/null || echo 0); n=$((n+1)); echo $n > "$1";
+            read line; echo "$line $n"; [ $n -ge 3 ]"#;
+
+        let lines = Arc::new(Mutex::new(Vec::<String>::new()));
+        let lines_cloned = Arc::clone(&lines);
+        let output = Cmd::builder("sh")
+            .args(["-c", script, "sh"])
+            .arg(&counter)
+            .stdin_data("hello\n")
+            .on_stdout_line(move |line| lines_cloned.lock().unwrap().push(String::from_utf8_lossy(line).to_string()))
+            .with_retry(RetryPolicy::new(5, Backoff::Fixed(Duration::from_millis(10))))
+            .build()
+            .output()
+            .unwrap();
+        std::fs::remove_file(&counter).unwrap();
+
+        assert!(output.success());
+        assert_eq!(3, output.attempts);
+        assert_eq!("hello 3\n", output.stdout.as_str().unwrap());
+        assert_eq!(vec!["hello 1", "hello 2", "hello 3"], *lines.lock().unwrap());
+
+        // the predicate decides which failures are retried
+        let policy = RetryPolicy::new(5, Backoff::Fixed(Duration::ZERO)).retry_if(|err| err.exit_code() == Some(75));
+        let output = Cmd::builder("sh").args(["-c", "exit 1"]).with_retry(policy.clone()).build().output().unwrap();
+        assert_eq!(1, output.attempts);
+
+        let output = Cmd::builder("sh").args(["-c", "exit 75"]).with_retry(policy).build().output().unwrap();
+        assert_eq!(5, output.attempts);
+        assert_eq!(
+            "`sh -c 'exit 75'` failed, after 5 attempts, exit code: 75, stdout: ",
+            CmdError::from(output).to_string()
+        );
+
+        // the cancel signal interrupts the backoff
+        let now = Instant::now();
+        let output = Cmd::builder("false")
+            .with_signal(cancel_signal(Duration::from_millis(200)).unwrap())
+            .with_retry(RetryPolicy::new(3, Backoff::Fixed(Duration::from_secs(10))))
+            .build()
+            .output()
+            .unwrap();
+        assert_eq!(1, output.attempts);
+        assert!(now.elapsed() < Duration::from_secs(5), "elapsed: {:?}", now.elapsed());
+
+        // every attempt gets the same stdio
+        let output = Cmd::builder("sh")
+            .args(["-c", "echo out; echo err >&2; exit 1"])
+            .stderr(None::<Stdio>)
+            .with_retry(RetryPolicy::new(2, Backoff::Fixed(Duration::ZERO)))
+            .build()
+            .output()
+            .unwrap();
+        assert_eq!(2, output.attempts);
+        assert_eq!("out\n", output.stdout.as_str().unwrap());
+        assert!(output.stderr.is_empty());
+
+        // a `Stdio` is set up again for every attempt
+        let output = Cmd::builder("sh")
+            .args(["-c", "cat; echo out; echo err >&2; exit 1"])
+            .stdin(Some(Stdio::null()))
+            .stdout(Some(Stdio::piped()))
+            .stderr(Some(Stdio::null()))
+            .with_retry(RetryPolicy::new(2, Backoff::Fixed(Duration::ZERO)))
+            .build()
+            .output()
+            .unwrap();
+        assert_eq!(2, output.attempts);
+        assert_eq!("out\n", output.stdout.as_str().unwrap());
+        assert!(output.stderr.is_empty());
+
+        // but a file would be shared by the attempts
+        let err = Cmd::builder("true")
+            .stdin(Some(std::fs::File::open("/dev/null").unwrap()))
+            .with_retry(RetryPolicy::new(2, Backoff::Fixed(Duration::ZERO)))
+            .build()
+            .output()
+            .unwrap_err();
+        assert!(matches!(err, Error::IoError(ref err) if err.kind() == std::io::ErrorKind::InvalidInput), "{err:?}");
+    }
+
+    #[test]
+    fn test_backoff() {
+        let initial = Duration::from_millis(100);
+        let max = Duration::from_secs(1);
+
+        assert_eq!(initial, Backoff::Fixed(initial).delay(3));
+        assert_eq!(initial, Backoff::Exponential { initial, max }.delay(1));
+        assert_eq!(Duration::from_millis(400), Backoff::Exponential { initial, max }.delay(3));
+        assert_eq!(max, Backoff::Exponential { initial, max }.delay(40));
+        for attempt in 1..10 {
+            let delay = Backoff::Jitter { initial, max }.delay(attempt);
+            assert!(delay <= Backoff::Exponential { initial, max }.delay(attempt));
+        }
+    }
+
+    #[test]
+    fn test_output_limits() {
+        init_log!();
+        let output = Cmd::builder("seq")
+            .args(["1", "100000"])
+            .max_stdout_bytes(10, Overflow::KeepHead)
+            .build()
+            .output()
+            .unwrap();
+        assert!(output.stdout_truncated);
+        assert!(!output.stderr_truncated);
+        assert!(output.stdout.as_str().unwrap().starts_with("1\n2\n3\n4\n5\n\n[... "), "{:?}", output.stdout.as_str());
+
+        let output = Cmd::builder("seq")
+            .args(["1", "100000"])
+            .max_stdout_bytes(13, Overflow::KeepTail)
+            .build()
+            .output()
+            .unwrap();
+        assert!(output.stdout.as_str().unwrap().ends_with(" bytes truncated ...]\n99999\n100000\n"));
+
+        let output = Cmd::builder("seq")
+            .args(["1", "100000"])
+            .max_stdout_bytes(20, Overflow::KeepBoth)
+            .build()
+            .output()
+            .unwrap();
+        let stdout = output.stdout.as_str().unwrap();
+        assert!(stdout.starts_with("1\n2\n3\n4\n5\n") && stdout.ends_with("\n100000\n"), "{stdout:?}");
+
+        let output = Cmd::builder("seq")
+            .args(["1", "100000"])
+            .max_stdout_bytes(1_000_000, Overflow::KeepHead)
+            .build()
+            .output()
+            .unwrap();
+        assert!(!output.stdout_truncated);
+        assert!(output.stdout.as_str().unwrap().ends_with("\n100000\n"));
+
+        // a runaway command is killed
+        let output = Cmd::builder("yes")
+            .max_stdout_bytes(1000, Overflow::Kill)
+            .with_timeout(Duration::from_secs(10))
+            .build()
+            .output()
+            .unwrap();
+        assert_eq!(Termination::OutputLimitExceeded, output.termination);
+        assert!(output.kill());
+        assert!(output.stdout_truncated);
+        assert!(output.stdout.starts_with(&b"y\n".repeat(500)));
+        assert!(CmdError::from(output).stdout_truncated);
+
+        // a line without newlines is passed to the callback in pieces
+        let pieces = Arc::new(Mutex::new(Vec::<usize>::new()));
+        let pieces_cloned = Arc::clone(&pieces);
+        let output = Cmd::builder("head")
+            .args(["-c", "3000000", "/dev/zero"])
+            .on_stdout_line(move |line| pieces_cloned.lock().unwrap().push(line.len()))
+            .capture_stdout(false)
+            .build()
+            .output()
+            .unwrap();
+        assert!(output.stdout.is_empty());
+        assert_eq!(vec![1024 * 1024, 1024 * 1024, 3_000_000 - 2 * 1024 * 1024], *pieces.lock().unwrap());
+
+        let pieces = Arc::new(Mutex::new(Vec::<usize>::new()));
+        let pieces_cloned = Arc::clone(&pieces);
+        Cmd::builder("head")
+            .args(["-c", "3000000", "/dev/zero"])
+            .on_stdout_line(move |line| pieces_cloned.lock().unwrap().push(line.len()))
+            .max_line_bytes(4 * 1024 * 1024)
+            .build()
+            .output()
+            .unwrap();
+        assert_eq!(vec![3_000_000], *pieces.lock().unwrap());
+    }
+
+    struct FailingWriter;
+
+    impl Write for FailingWriter {
+        fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::other("disk full"))
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_tee() {
+        init_log!();
+        let path = std::env::temp_dir().join(format!("simple-cmd-tee-{}", std::process::id()));
+
+        let output = Cmd::builder("sh")
+            .args(["-c", "echo first; echo err >&2"])
+            .tee_stdout_file(&path, false)
+            .unwrap()
+            .build()
+            .output()
+            .unwrap();
+        assert_eq!("first\n", output.stdout.as_str().unwrap());
+        assert_eq!("first\n", std::fs::read_to_string(&path).unwrap());
+
+        let builder = Cmd::builder("sh")
+            .args(["-c", "echo second; echo err >&2"])
+            .tee_stdout_file(&path, true)
+            .unwrap();
+        builder.tee_stderr_file(&path, true).unwrap().build().output().unwrap();
+        assert_eq!("first\nsecond\nerr\n", std::fs::read_to_string(&path).unwrap());
+
+        Cmd::builder("sh")
+            .args(["-c", "echo third; echo err >&2"])
+            .tee_stdout_file(&path, false)
+            .unwrap()
+            .build()
+            .output()
+            .unwrap();
+        assert_eq!("third\n", std::fs::read_to_string(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        // the command keeps running when the tee fails
+        let err = Cmd::builder("sh")
+            .args(["-c", "echo a; sleep 0.2; echo b"])
+            .tee_stdout(FailingWriter)
+            .build()
+            .output()
+            .unwrap_err();
+        match err {
+            // the status and the output are kept
+            Error::Tee { stream, status, stdout, stderr, .. } => {
+                assert_eq!("stdout", stream);
+                assert!(status.success());
+                assert_eq!("a\nb\n", stdout.as_str().unwrap());
+                assert!(stderr.is_empty());
+            }
+            _ => panic!("unexpected error: {err:?}"),
+        }
+
+        let err = Cmd::builder("sh")
+            .args(["-c", "echo a; echo err >&2"])
+            .tee_stderr(FailingWriter)
+            .build()
+            .output()
+            .unwrap_err();
+        assert_eq!("failed to write the stderr of the command to its tee: disk full", err.to_string());
+
+        // unless it is asked to be killed
+        let start = Instant::now();
+        let err = Cmd::builder("yes")
+            .tee_stdout(FailingWriter)
+            .kill_on_tee_error(true)
+            .with_timeout(Duration::from_secs(10))
+            .build()
+            .output()
+            .unwrap_err();
+        assert!(matches!(err, Error::Tee { stream: "stdout", status, .. } if status.signal() == Some(9)), "{err:?}");
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let err = Cmd::builder("sh")
+            .args(["-c", "echo a; echo err >&2"])
+            .build()
+            .pipe(Cmd::builder("cat").tee_stdout(FailingWriter))
+            .output()
+            .unwrap_err();
+        assert!(matches!(&err, Error::Tee { stream: "stdout", stdout, .. } if stdout == b"a\n"), "{err:?}");
+    }
+
+    #[test]
+    fn test_merge_stderr() {
+        init_log!();
+        let output = Cmd::builder("sh")
+            .args(["-c", "echo 1; echo 2 >&2; echo 3; echo 4 >&2"])
+            .merge_stderr(true)
+            .build()
+            .output()
+            .unwrap();
+        assert_eq!("1\n2\n3\n4\n", output.stdout.as_str().unwrap());
+        assert!(output.stderr.is_empty());
+
+        let output = Cmd::builder("sh")
+            .args(["-c", "echo 1; echo 2 >&2; echo 3; echo 4 >&2"])
+            .merge_stderr(true)
+            .build()
+            .spawn()
+            .unwrap()
+            .wait_with_output()
+            .unwrap();
+        assert_eq!("1\n2\n3\n4\n", output.stdout.as_str().unwrap());
+
+        let output = Cmd::builder("sh")
+            .args(["-c", "echo 1; echo 2 >&2; echo 3; echo 4 >&2"])
+            .merge_stderr(true)
+            .build()
+            .pipe(Cmd::builder("wc").arg("-l"))
+            .output()
+            .unwrap();
+        assert_eq!("4", output.stdout.as_str().unwrap().trim());
+        assert!(output.stage_stderr[0].is_empty());
+    }
+
+    #[test]
+    fn test_success_policy() {
+        init_log!();
+        // the stderr doesn't matter by default
+        let stdout = Cmd::builder("sh")
+            .args(["-c", "echo done; echo 'progress 50%' >&2; exit ${CODE:-0}"])
+            .build()
+            .output_checked()
+            .unwrap();
+        assert_eq!(b"done\n".to_vec(), stdout);
+
+        let err = Cmd::builder("sh")
+            .args(["-c", "echo done; echo 'progress 50%' >&2; exit ${CODE:-0}"])
+            .with_success_policy(SuccessPolicy::strict())
+            .build()
+            .output_checked()
+            .unwrap_err();
+        let Error::CommandError(err) = err else { panic!("{err:?}") };
+        assert_eq!(Some(0), err.exit_code());
+        assert_eq!("progress 50%\n", err.stderr.as_str().unwrap());
+
+        let policy = SuccessPolicy::new().exit_codes([0, 1]);
+        let output = Cmd::builder("sh")
+            .args(["-c", "echo done; echo 'progress 50%' >&2; exit ${CODE:-0}"])
+            .env("CODE", "1")
+            .with_success_policy(policy.clone())
+            .build()
+            .output_checked();
+        assert_eq!(b"done\n".to_vec(), output.unwrap());
+
+        let output = Cmd::builder("sh")
+            .args(["-c", "echo done; echo 'progress 50%' >&2; exit ${CODE:-0}"])
+            .env("CODE", "2")
+            .with_success_policy(policy)
+            .build()
+            .output_checked();
+        assert!(output.is_err());
+
+        let policy = SuccessPolicy::new().fail_if_stderr_contains("fatal:");
+        let output = Cmd::builder("sh")
+            .args(["-c", "echo done; echo 'progress 50%' >&2; exit ${CODE:-0}"])
+            .with_success_policy(policy.clone())
+            .build()
+            .output_checked();
+        assert!(output.is_ok());
+        let output = Cmd::builder("sh").args(["-c", "echo 'fatal: not a repository' >&2"]).build().output().unwrap();
+        assert!(output.clone().into_result(&policy).is_err());
+        assert!(Output::from(output).into_result(&SuccessPolicy::new()).is_ok());
+
+        #[cfg(feature = "regex")]
+        {
+            let regex = regex::Regex::new(r"(?m)^(fatal|error):").unwrap();
+            let policy = SuccessPolicy::new().fail_if_stderr_matches(regex);
+            let output = Cmd::builder("sh")
+                .args(["-c", "echo done; echo 'progress 50%' >&2; exit ${CODE:-0}"])
+                .with_success_policy(policy.clone())
+                .build()
+                .output_checked();
+            assert!(output.is_ok());
+            let output = Cmd::builder("sh").args(["-c", "echo 'error: no such file' >&2"]).build().output().unwrap();
+            assert!(output.into_result(&policy).is_err());
+        }
+
+        // a killed command has failed whatever its policy
+        let output = Cmd::builder("sleep")
+            .arg("10")
+            .with_timeout(Duration::from_millis(100))
+            .build()
+            .output()
+            .unwrap();
+        assert!(output.into_result(&SuccessPolicy::new().exit_codes(0..256)).is_err());
+    }
+
+    #[test]
+    fn test_text_helpers() {
+        init_log!();
+        let output = Cmd::builder("printf")
+            .arg(r"  caf\351\r\nline 2\r\n\033[1;31mred\033[0m \033]0;title\007done  \n")
+            .build()
+            .output()
+            .unwrap();
+
+        assert_eq!(None, output.stdout.as_str());
+        assert!(output.stdout_lossy().starts_with("  caf\u{fffd}\r\n"));
+        assert!(output.stdout_trimmed().starts_with("caf\u{fffd}\r\n"));
+        assert!(output.stdout_trimmed().ends_with("done"));
+        assert_eq!(3, output.stdout_lines().len());
+        assert_eq!("line 2", output.stdout_lines()[1]);
+        assert!(output.stdout.crlf_normalized().starts_with("  caf\u{fffd}\nline 2\n"));
+        assert_eq!("red done", output.stdout.ansi_stripped().lines().last().unwrap().trim());
+        assert!(output.stderr_lines().is_empty());
+        assert!(output.stderr_lossy().is_empty());
+
+        assert_eq!(b"a\r\nb\n".to_vec().lines_lossy(), vec!["a", "b"]);
+        assert_eq!("ab", b"a\x1b7\x1b[Kb\x1b".to_vec().ansi_stripped());
+        assert_eq!("bold plain", b"\x1b[1mbold\x1b(B\x1b[m plain".to_vec().ansi_stripped());
+    }
+
+    #[test]
+    fn test_cmd_output() {
+        init_log!();
+        let before = SystemTime::now();
+        let output = Cmd::builder("sh").args(["-c", "echo $$; sleep 0.2"]).build().output().unwrap();
+
+        assert_eq!(Termination::Exited, output.termination);
+        assert_eq!("sh -c 'echo $$; sleep 0.2'", output.command_line);
+        assert_eq!(output.pid.to_string(), output.stdout.as_str().unwrap().trim());
+        assert!(output.started_at >= before);
+        assert!(output.elapsed >= Duration::from_millis(200), "elapsed: {:?}", output.elapsed);
+        assert!(Output::from(output).success());
+
+        // killed by someone else
+        let output = Cmd::builder("sh")
+            .args(["-c", "kill -9 $$"])
+            .with_timeout(Duration::from_secs(5))
+            .build()
+            .output()
+            .unwrap();
+        assert_eq!(Termination::Signaled(signal_hook::consts::SIGKILL), output.termination);
+        assert!(output.kill());
+        assert!(!output.timed_out());
+
+        // the timeout is reported even when the command handles the signal and exits
+        let output = Cmd::builder("sh")
+            .args(["-c", "trap 'exit 3' TERM; sleep 10 & wait"])
+            .with_timeout(Duration::from_millis(200))
+            .with_termination(TermPolicy::graceful(Duration::from_secs(5)))
+            .kill_tree(true)
+            .build()
+            .output()
+            .unwrap();
+        assert_eq!(Some(3), output.status.code());
+        assert_eq!(Termination::TimedOut, output.termination);
     }
 
     #[test]
@@ -192,13 +682,204 @@ mod tests {
         assert_eq!(cmd_string, "sleep 1 2".to_string());
     }
 
+    #[test]
+    fn test_parse() {
+        let builder = CommandBuilder::parse("git --no-pager log --format='%H %s'").unwrap();
+        let cmd = builder.build();
+        assert_eq!("git", cmd.program);
+        assert_eq!(vec!["--no-pager", "log", "--format=%H %s"], cmd.args);
+
+        let builder: CommandBuilder = r#"echo "a \"b\" $HOME" c\ d '' 'it'\''s'"#.parse().unwrap();
+        let cmd = builder.build();
+        assert_eq!(vec!["a \"b\" $HOME", "c d", "", "it's"], cmd.args);
+
+        let builder = Cmd::builder("printf").args(["%s\\n", "it's", "", "a b", "plain"]);
+        let line = builder.to_string();
+        assert_eq!(r#"printf '%s\n' 'it'\''s' '' 'a b' plain"#, line);
+        assert_eq!(line, CommandBuilder::parse(&line).unwrap().to_string());
+
+        for line in ["echo 'hello", "echo \"hello", "echo hello\\", "  "] {
+            let err = CommandBuilder::parse(line).unwrap_err();
+            assert!(matches!(err, Error::Parse { .. }), "{line}: {err}");
+        }
+        assert_eq!(
+            "failed to parse `echo 'hello`: unterminated single quote at offset 5",
+            CommandBuilder::parse("echo 'hello").unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn test_as_string() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let cmd = Cmd::builder("/bin/echo")
+            .args(["it's", "a b", ""])
+            .arg(OsStr::from_bytes(b"caf\xe9\n"))
+            .current_dir("/my dir")
+            .env("FOO", "foo bar")
+            .env_remove("BAR")
+            .build();
+        assert_eq!(
+            r"cd '/my dir' && env -u BAR FOO='foo bar' /bin/echo 'it'\''s' 'a b' '' $'caf\xe9\x0a'",
+            cmd.as_string()
+        );
+        assert_eq!(r"/bin/echo 'it'\''s' 'a b' '' $'caf\xe9\x0a'", cmd.to_string());
+
+        let cmd = Cmd::builder("env").env("A", "1").env_remove("A").env_remove("B").env("B", "2").build();
+        assert_eq!("env -u A B=2 env", cmd.as_string());
+
+        let cmd = Cmd::builder("env").env_inherit_only(["PATH"]).env("FOO", "1").build();
+        assert_eq!(r#"env -i PATH="$PATH" FOO=1 env"#, cmd.as_string());
+
+        let mut command = Command::new("ls");
+        command.arg("-l").current_dir("/tmp");
+        assert_eq!("cd /tmp && ls -l", command.as_string());
+    }
+
+    #[test]
+    fn test_secrets() {
+        let builder = Cmd::builder("sh")
+            .args(["-c", "echo $1 $TOKEN", "sh"])
+            .secret_arg("hunter2")
+            .secret_env("TOKEN", "s3cr3t")
+            .env("USER_NAME", "me");
+        assert_eq!("sh -c 'echo $1 $TOKEN' sh '****'", builder.to_string());
+        let debug = format!("{builder:?}");
+        assert!(!debug.contains("hunter2") && !debug.contains("s3cr3t"));
+        assert!(debug.contains("USER_NAME") && debug.contains("TOKEN"));
+
+        let cmd = builder.build();
+        assert_eq!("TOKEN='****' USER_NAME=me sh -c 'echo $1 $TOKEN' sh '****'", cmd.as_string());
+        assert!(!format!("{cmd:?}").contains("hunter2") && !format!("{cmd:?}").contains("s3cr3t"));
+
+        let status = std::process::ExitStatus::from_raw(1 << 8);
+        let err = CmdError::from_err(status, vec![], b"denied".to_vec()).with_command(&cmd);
+        assert_eq!(
+            "`TOKEN='****' USER_NAME=me sh -c 'echo $1 $TOKEN' sh '****'` failed, exit code: 1, stderr: denied",
+            err.to_string()
+        );
+
+        // a real `****` argument must not be expanded by the shell the line is pasted into
+        let literal = Cmd::builder("echo").arg("****").build();
+        assert_eq!("echo '****'", literal.as_string());
+        assert_eq!(vec!["echo", "****"], crate::shell::split(&literal.as_string()).unwrap());
+
+        // the child still receives the real values
+        let output = cmd.output().unwrap();
+        assert_eq!("hunter2 s3cr3t", output.stdout.as_str().unwrap().trim());
+
+        let err = Cmd::builder("simple-cmd-does-not-exist")
+            .arg("--token")
+            .secret_arg("hunter2")
+            .build()
+            .output()
+            .unwrap_err();
+        assert!(!format!("{err:?}").contains("hunter2"));
+    }
+
+    /// The name and the fields of a span.
+    type SpanFields = (String, HashMap<String, String>);
+
+    /// Records the fields of the closed spans.
+    #[derive(Clone, Default)]
+    struct SpanRecorder {
+        open: Arc<Mutex<HashMap<u64, SpanFields>>>,
+        closed: Arc<Mutex<Vec<SpanFields>>>,
+    }
+
+    struct FieldVisitor<'a>(&'a mut HashMap<String, String>);
+
+    impl tracing::field::Visit for FieldVisitor<'_> {
+        fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+            self.0.insert(field.name().to_string(), value.to_string());
+        }
+
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            self.0.insert(field.name().to_string(), format!("{value:?}"));
+        }
+    }
+
+    impl<S: tracing::Subscriber> Layer<S> for SpanRecorder {
+        fn on_new_span(&self, attrs: &tracing::span::Attributes<'_>, id: &tracing::span::Id, _ctx: Context<'_, S>) {
+            let mut fields = HashMap::new();
+            attrs.record(&mut FieldVisitor(&mut fields));
+            fields.insert("target".to_string(), attrs.metadata().target().to_string());
+            self.open.lock().unwrap().insert(id.into_u64(), (attrs.metadata().name().to_string(), fields));
+        }
+
+        fn on_record(&self, id: &tracing::span::Id, values: &tracing::span::Record<'_>, _ctx: Context<'_, S>) {
+            if let Some((_, fields)) = self.open.lock().unwrap().get_mut(&id.into_u64()) {
+                values.record(&mut FieldVisitor(fields));
+            }
+        }
+
+        fn on_close(&self, id: tracing::span::Id, _ctx: Context<'_, S>) {
+            if let Some(span) = self.open.lock().unwrap().remove(&id.into_u64()) {
+                self.closed.lock().unwrap().push(span);
+            }
+        }
+    }
+
+    #[test]
+    fn test_tracing_spans() {
+        let recorder = SpanRecorder::default();
+        let subscriber = tracing_subscriber::registry().with(recorder.clone());
+
+        tracing::subscriber::with_default(subscriber, || {
+            Cmd::builder("sh")
+                .args(["-c", "exit 3"])
+                .secret_arg("hunter2")
+                .current_dir("/")
+                .with_timeout(Duration::from_secs(5))
+                .with_trace_level(tracing::Level::INFO)
+                .with_trace_target(TraceTarget::Audit)
+                .with_trace_label("exit")
+                .build()
+                .output()
+                .unwrap();
+
+            Pipeline::new()
+                .stage(Cmd::builder("echo").arg("hello"))
+                .stage(Cmd::builder("sh").args(["-c", "kill -9 $$"]))
+                .with_trace_target(TraceTarget::Metrics)
+                .output()
+                .unwrap();
+        });
+
+        let closed = recorder.closed.lock().unwrap();
+        let names = closed.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>();
+        assert_eq!(vec!["cmd", "cmd", "cmd", "cmd_pipeline"], names);
+
+        let fields = &closed[0].1;
+        assert_eq!("output", fields["operation"]);
+        assert_eq!("simple_cmd::audit", fields["target"]);
+        assert_eq!("exit", fields["label"]);
+        assert_eq!("sh", fields["program"]);
+        assert_eq!("-c 'exit 3' '****'", fields["args"]);
+        assert_eq!("/", fields["cwd"]);
+        assert_eq!("5.0", fields["timeout"]);
+        assert_eq!("3", fields["exit_code"]);
+        assert!(fields.contains_key("pid"));
+        assert!(fields.contains_key("duration"));
+        assert!(!fields.contains_key("signal"));
+
+        let fields = &closed[2].1;
+        assert_eq!("pipe", fields["operation"]);
+        assert_eq!("9", fields["signal"]);
+        assert_eq!("simple_cmd", fields["target"]);
+        assert_eq!("9", closed[3].1["signal"]);
+        assert_eq!("simple_cmd::metrics", closed[3].1["target"]);
+        assert_eq!("2", closed[3].1["stages"]);
+    }
+
     #[test]
     fn test_git_shortlog() {
         init_log!();
         let builder = Cmd::builder("git")
             .current_dir("/Users/alessandro/Documents/git/swisscom/aot-lib")
             .with_arg("--no-pager")
-            .with_args(&["shortlog", "-sne", "--all"])
+            .with_args(["shortlog", "-sne", "--all"])
             .with_debug(true)
             .with_timeout(Duration::from_secs(2));
 
@@ -207,7 +888,7 @@ mod tests {
         println!("output: {:?}", output);
 
         if let Ok(output) = output {
-            for line in output.stdout.lines().into_iter() {
+            for line in output.stdout.lines() {
                 println!("line: {:?}", line);
             }
         }
@@ -216,18 +897,719 @@ mod tests {
     #[test]
     fn test_pipe() {
         init_log!();
-        let builder = Cmd::builder("echo").args(&["hello pretty world"]).with_debug(true);
+        let builder = Cmd::builder("echo").args(["hello pretty world"]).with_debug(true);
 
         let command1 = builder.build();
 
         let mut command2 = Command::new("sed");
-        command2.args(&["s/pretty/_/"]);
+        command2.args(["s/pretty/_/"]);
         command2.stdout(Stdio::piped());
 
-        let result = command1.pipe(command2).unwrap();
+        let result = command1.pipe(command2).output().unwrap();
         let output = result.stdout.as_str().unwrap().trim();
 
         assert!(result.success());
         assert_eq!("hello _ world", output);
     }
+
+    #[test]
+    fn test_pipe_timeout() {
+        init_log!();
+        // the timeout of the left-hand side covers the whole pipe
+        let now = Instant::now();
+        let output = Cmd::builder("sleep")
+            .arg("10")
+            .with_timeout(Duration::from_millis(200))
+            .build()
+            .pipe(Cmd::builder("sleep").arg("3"))
+            .output()
+            .unwrap();
+        assert!(now.elapsed() < Duration::from_secs(2), "elapsed: {:?}", now.elapsed());
+        assert!(output.statuses.iter().all(|s| s.signal() == Some(signal_hook::consts::SIGKILL)));
+    }
+
+    #[test]
+    fn test_pipe_chain() {
+        init_log!();
+        let dir = std::env::temp_dir();
+        let pipeline = Cmd::builder("echo")
+            .args(["hello pretty world"])
+            .build()
+            .pipe(Cmd::builder("sed").args(["s/pretty/_/"]).with_timeout(Duration::from_secs(5)))
+            .pipe(Cmd::builder("sh").args(["-c", "pwd; cat"]).current_dir(&dir));
+
+        assert_eq!(
+            format!("echo 'hello pretty world' | sed s/pretty/_/ | (cd {} && sh -c 'pwd; cat')", dir.display()),
+            pipeline.as_string()
+        );
+
+        let result = pipeline.output().unwrap();
+        let mut lines = result.stdout.as_str().unwrap().lines();
+
+        assert!(result.success());
+        assert_eq!(Some(dir.canonicalize().unwrap().to_str().unwrap()), lines.next());
+        assert_eq!(Some("hello _ world"), lines.next());
+    }
+
+    #[test]
+    fn test_env() {
+        init_log!();
+        let output = Cmd::builder("sh")
+            .args(["-c", "echo $SIMPLE_CMD_FOO-$SIMPLE_CMD_BAR"])
+            .env("SIMPLE_CMD_FOO", "foo")
+            .envs([("SIMPLE_CMD_BAR", "bar")])
+            .with_debug(true)
+            .build()
+            .output()
+            .unwrap();
+        assert_eq!("foo-bar", output.stdout.as_str().unwrap().trim());
+
+        let output = Cmd::builder("sh")
+            .args(["-c", "echo ${HOME:-none}"])
+            .env_remove("HOME")
+            .build()
+            .output()
+            .unwrap();
+        assert_eq!("none", output.stdout.as_str().unwrap().trim());
+    }
+
+    #[test]
+    fn test_env_clear() {
+        init_log!();
+        let output = Cmd::builder("/usr/bin/env")
+            .env("SIMPLE_CMD_FOO", "foo")
+            .env_clear()
+            .env("SIMPLE_CMD_BAR", "bar")
+            .build()
+            .output()
+            .unwrap();
+        assert_eq!("SIMPLE_CMD_BAR=bar", output.stdout.as_str().unwrap().trim());
+
+        let mut command: Command = Cmd::builder("/usr/bin/env")
+            .env_inherit_only(["PATH"])
+            .env("SIMPLE_CMD_FOO", "foo")
+            .into();
+        let output = command.output().unwrap();
+        let stdout = output.stdout.as_str().unwrap();
+        let mut vars = stdout.lines().map(|l| l.split('=').next().unwrap()).collect::<Vec<_>>();
+        vars.sort();
+        assert_eq!(vec!["PATH", "SIMPLE_CMD_FOO"], vars);
+    }
+
+    #[test]
+    fn test_spawn_error() {
+        init_log!();
+        let err = Cmd::builder("simple-cmd-does-not-exist").arg("1").build().output().unwrap_err();
+        assert!(err.is_spawn());
+        assert!(err.is_not_found());
+        match err {
+            crate::Error::Spawn { program, args, cwd, .. } => {
+                assert_eq!("simple-cmd-does-not-exist", program);
+                assert_eq!(vec!["1"], args);
+                assert!(cwd.is_none());
+            }
+            _ => unreachable!(),
+        }
+
+        let err = Cmd::builder("ls").current_dir("/simple-cmd/does/not/exist").build().run().unwrap_err();
+        assert!(err.is_not_found());
+
+        let err = Cmd::builder("/dev/null").build().output().unwrap_err();
+        assert!(err.is_permission_denied());
+
+        let command2 = Command::new("simple-cmd-does-not-exist");
+        let err = Cmd::builder("echo").arg("hello").build().pipe(command2).output().unwrap_err();
+        assert!(err.is_not_found());
+    }
+
+    #[test]
+    fn test_graceful_termination() {
+        init_log!();
+        let cmd = Cmd::builder("sleep")
+            .arg("10")
+            .with_timeout(Duration::from_millis(100))
+            .with_termination(TermPolicy::graceful(Duration::from_secs(5)))
+            .with_debug(true)
+            .build();
+        let output = cmd.output().expect("failed to wait for command");
+        assert!(output.terminate());
+        assert!(!output.kill());
+
+        // SIGTERM is ignored, so SIGKILL must be sent once the grace period expires
+        let now = Instant::now();
+        let cmd = Cmd::builder("sh")
+            .args(["-c", "trap '' TERM; exec sleep 10"])
+            .with_timeout(Duration::from_millis(100))
+            .with_termination(TermPolicy::graceful(Duration::from_millis(500)))
+            .with_debug(true)
+            .build();
+        let output = cmd.output().expect("failed to wait for command");
+        let elapsed = now.elapsed();
+        assert!(output.kill());
+        assert!(!output.terminate());
+        assert!(elapsed >= Duration::from_millis(600), "elapsed: {:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(5), "elapsed: {:?}", elapsed);
+    }
+
+    #[test]
+    fn test_kill_tree() {
+        init_log!();
+        // `sleep` is a grandchild holding the stdout pipe: killing only `sh` would block until it exits
+        let now = Instant::now();
+        let cmd = Cmd::builder("sh")
+            .args(["-c", "sleep 10 & wait"])
+            .with_timeout(Duration::from_millis(200))
+            .kill_tree(true)
+            .with_debug(true)
+            .build();
+        let output = cmd.output().expect("failed to wait for command");
+        assert!(output.kill());
+        assert!(now.elapsed() < Duration::from_secs(5), "elapsed: {:?}", now.elapsed());
+
+        // `sh` exits right away and is reaped, leaving `sleep` alone in the process group
+        let now = Instant::now();
+        let cmd = Cmd::builder("sh")
+            .args(["-c", "sleep 10 &"])
+            .with_timeout(Duration::from_millis(300))
+            .kill_tree(true)
+            .build();
+        let output = cmd.output().expect("failed to wait for command");
+        assert!(output.success());
+        assert_eq!(Termination::TimedOut, output.termination);
+        assert!(now.elapsed() < Duration::from_secs(5), "elapsed: {:?}", now.elapsed());
+
+        let command2 = Command::new("cat");
+        let now = Instant::now();
+        let cmd = Cmd::builder("sh")
+            .args(["-c", "sleep 10 & wait"])
+            .with_timeout(Duration::from_millis(200))
+            .kill_tree(true)
+            .build();
+        let output = cmd.pipe(command2).output().expect("failed to wait for command");
+        assert!(Output::from(output).kill());
+        assert!(now.elapsed() < Duration::from_secs(5), "elapsed: {:?}", now.elapsed());
+    }
+
+    #[test]
+    fn test_large_stderr() {
+        init_log!();
+        // stderr is filled well beyond the pipe buffer before anything is written to stdout
+        let cmd = Cmd::builder("sh")
+            .args(["-c", "head -c 4000000 /dev/zero >&2; echo done"])
+            .with_timeout(Duration::from_secs(10))
+            .build();
+        let output = cmd.output().expect("failed to wait for command");
+        assert!(output.success());
+        assert_eq!(4_000_000, output.stderr.len());
+        assert_eq!("done", output.stdout.as_str().unwrap().trim());
+    }
+
+    /// The cpu time used so far by the threads of this process named as one of `names`, by thread id.
+    #[cfg(target_os = "linux")]
+    fn threads_cpu_time(names: &[&str]) -> HashMap<String, Duration> {
+        let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as u64;
+        let mut times = HashMap::new();
+        for task in std::fs::read_dir("/proc/self/task").unwrap().flatten() {
+            // the thread may have exited in the meantime
+            let (Ok(name), Ok(stat)) = (
+                std::fs::read_to_string(task.path().join("comm")),
+                std::fs::read_to_string(task.path().join("stat")),
+            ) else {
+                continue;
+            };
+            if !names.contains(&name.trim()) {
+                continue;
+            }
+            // utime and stime are the 12th and 13th fields after the name, which is between parentheses
+            let fields = stat[stat.rfind(')').unwrap() + 2..].split(' ').collect::<Vec<_>>();
+            let ticks = fields[11].parse::<u64>().unwrap() + fields[12].parse::<u64>().unwrap();
+            let time = Duration::from_millis(ticks * 1000 / ticks_per_second);
+            times.insert(task.file_name().to_string_lossy().into_owned(), time);
+        }
+        times
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_wait_cpu_usage() {
+        init_log!();
+        let (_sender, receiver) = bounded::<()>(1);
+        let cmd = Cmd::builder("sleep")
+            .arg("2")
+            .with_timeout(Duration::from_secs(10))
+            .with_signal(receiver)
+            .build();
+
+        // only the threads waiting for the command are measured, not the other tests running in parallel
+        let names = ["cmd_exit", "cmd_wait", "cmd_stderr", "cpu_usage_test"];
+        let thread = thread::Builder::new().name("cpu_usage_test".to_string()).spawn(move || cmd.output()).unwrap();
+        sleep(Duration::from_millis(500));
+        let before = threads_cpu_time(&names);
+        sleep(Duration::from_secs(1));
+        let after = threads_cpu_time(&names);
+
+        let output = thread.join().unwrap().expect("failed to wait for command");
+        assert!(output.success());
+        assert!(before.len() >= 4, "threads: {before:?}");
+        // a thread polling in a loop would burn most of the second
+        let busiest = after
+            .iter()
+            .filter_map(|(tid, cpu)| before.get(tid).map(|before| cpu.saturating_sub(*before)))
+            .max();
+        assert!(busiest.unwrap() < Duration::from_millis(200), "cpu time: {busiest:?}");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_output_json() {
+        #[derive(Debug, PartialEq, serde::Deserialize)]
+        struct Package {
+            name: String,
+            version: u32,
+        }
+
+        init_log!();
+        let package: Package = Cmd::builder("echo")
+            .arg(r#"{"name": "simple-cmd", "version": 22}"#)
+            .build()
+            .output_json()
+            .unwrap();
+        assert_eq!(Package { name: "simple-cmd".to_string(), version: 22 }, package);
+
+        let err = Cmd::builder("echo")
+            .arg(r#"{"name": "simple-cmd", "version": "22"}"#)
+            .build()
+            .output_json::<Package>();
+        let Err(Error::Json { command, status, snippet, .. }) = err else { panic!("{err:?}") };
+        assert!(command.starts_with("echo "));
+        assert!(status.unwrap().success());
+        assert!(snippet.contains(r#""version": "22""#), "{snippet}");
+
+        let err = Cmd::builder("sh").args(["-c", "echo '{}'; exit 3"]).build().output_json::<Package>().unwrap_err();
+        let Error::CommandError(err) = err else { panic!("{err:?}") };
+        assert_eq!(Some(3), err.exit_code());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_stream_json_lines() {
+        #[derive(Debug, serde::Deserialize)]
+        struct Message {
+            n: u32,
+        }
+
+        init_log!();
+        let lines = Cmd::builder("sh")
+            .args(["-c", r#"printf '{"n": 1}\n\n{"n": 2}\nwarning\n' ; echo log >&2; exit ${CODE:-0}"#])
+            .build()
+            .stream_json_lines::<Message>()
+            .unwrap()
+            .collect::<Vec<_>>();
+        assert_eq!(3, lines.len());
+        assert_eq!(1, lines[0].as_ref().unwrap().n);
+        assert_eq!(2, lines[1].as_ref().unwrap().n);
+        assert!(matches!(&lines[2], Err(Error::Json { status: None, snippet, .. }) if snippet == "warning"));
+
+        let mut lines = Cmd::builder("sh")
+            .args(["-c", r#"echo '{"n": 1}'; exit 2"#])
+            .build()
+            .stream_json_lines::<Message>()
+            .unwrap();
+        assert_eq!(1, lines.next().unwrap().unwrap().n);
+        assert!(matches!(lines.next(), Some(Err(Error::CommandError(err))) if err.exit_code() == Some(2)));
+        assert!(lines.next().is_none());
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_output_async() {
+        init_log!();
+        let output = Cmd::builder("echo")
+            .arg("hello")
+            .with_debug(true)
+            .build()
+            .output_async()
+            .await
+            .expect("failed to wait for command");
+        assert!(output.success());
+        assert_eq!("hello", output.stdout.as_str().unwrap().trim());
+        assert_eq!("echo hello", output.command_line);
+        assert!(output.pid > 0);
+
+        let output = Cmd::builder("sleep")
+            .arg("10")
+            .with_timeout(Duration::from_millis(100))
+            .build()
+            .output_async()
+            .await
+            .expect("failed to wait for command");
+        assert!(output.kill());
+        assert!(output.timed_out());
+        assert!(output.elapsed < Duration::from_secs(5));
+
+        // `sh` exits right away and is reaped, leaving `sleep` alone in the process group with the stdout pipe
+        let output = Cmd::builder("sh")
+            .args(["-c", "sleep 10 &"])
+            .with_timeout(Duration::from_millis(300))
+            .kill_tree(true)
+            .build()
+            .output_async()
+            .await
+            .expect("failed to wait for command");
+        assert!(output.success());
+        assert_eq!(Termination::TimedOut, output.termination);
+        assert!(output.elapsed < Duration::from_secs(5), "elapsed: {:?}", output.elapsed);
+
+        let output = Cmd::builder("yes")
+            .max_stdout_bytes(1000, Overflow::Kill)
+            .with_timeout(Duration::from_secs(10))
+            .build()
+            .output_async()
+            .await
+            .expect("failed to wait for command");
+        assert!(output.kill());
+        assert!(output.stdout_truncated);
+        assert_eq!(Termination::OutputLimitExceeded, output.termination);
+        assert!(output.stdout.starts_with(&b"y\n".repeat(500)));
+
+        let output = Cmd::builder("sh")
+            .args(["-c", "echo out; echo err >&2"])
+            .merge_stderr(true)
+            .build()
+            .output_async()
+            .await
+            .expect("failed to wait for command");
+        assert_eq!("out\nerr\n", output.stdout.as_str().unwrap());
+        assert!(output.stderr.is_empty());
+
+        let cancel_signal = cancel_signal(Duration::from_millis(100)).unwrap();
+        let output = Cmd::builder("sleep")
+            .arg("10")
+            .with_signal(cancel_signal)
+            .with_termination(TermPolicy::graceful(Duration::from_secs(1)))
+            .build()
+            .output_async()
+            .await
+            .expect("failed to wait for command");
+        assert!(output.terminate());
+        assert!(output.cancelled());
+
+        let err = Cmd::builder("sleep")
+            .arg("5")
+            .with_timeout(Duration::from_millis(200))
+            .error_on_termination(true)
+            .build()
+            .output_async()
+            .await
+            .unwrap_err();
+        assert!(err.is_timeout());
+
+        let err = Cmd::builder("simple-cmd-does-not-exist").build().output_async().await.unwrap_err();
+        assert!(err.is_not_found());
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_pipe_async() {
+        init_log!();
+        let mut command2 = Command::new("sed");
+        command2.args(["s/pretty/_/"]);
+        command2.stdout(Stdio::piped());
+
+        let output = Cmd::builder("echo")
+            .args(["hello pretty world"])
+            .with_debug(true)
+            .build()
+            .pipe_async(command2)
+            .await
+            .unwrap();
+        assert!(output.success());
+        assert_eq!("hello _ world", output.stdout.as_str().unwrap().trim());
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_pipeline_output_async() {
+        init_log!();
+        let output = Cmd::builder("sh")
+            .args(["-c", "echo one; echo two; echo err >&2; exit 3"])
+            .build()
+            .pipe(Cmd::builder("sort").arg("-r"))
+            .pipe(Cmd::builder("head").arg("-n1"))
+            .pipefail(true)
+            .output_async()
+            .await
+            .unwrap();
+        assert_eq!(Some(3), output.status.code());
+        assert_eq!(vec![Some(3), Some(0), Some(0)], output.statuses.iter().map(|s| s.code()).collect::<Vec<_>>());
+        assert_eq!("two\n", output.stdout.as_str().unwrap());
+        assert_eq!("err\n", output.stage_stderr[0].as_str().unwrap());
+
+        let now = Instant::now();
+        let output = Cmd::builder("sleep")
+            .arg("10")
+            .with_timeout(Duration::from_millis(200))
+            .build()
+            .pipe_async(Cmd::builder("sleep").arg("3"))
+            .await
+            .unwrap();
+        assert!(now.elapsed() < Duration::from_secs(2));
+        assert!(output.statuses.iter().all(|s| s.signal() == Some(9)));
+
+        let output = Cmd::builder("echo")
+            .arg("hello")
+            .build()
+            .pipe(
+                Cmd::builder("sh")
+                    .args(["-c", "sleep 10; cat"])
+                    .with_timeout(Duration::from_millis(200))
+                    .kill_tree(true),
+            )
+            .output_async()
+            .await
+            .unwrap();
+        assert!(output.statuses[0].success());
+        assert_eq!(Some(9), output.status.signal());
+    }
+
+    #[test]
+    fn test_line_callbacks() {
+        init_log!();
+        let stdout_lines = Arc::new(Mutex::new(Vec::<String>::new()));
+        let stderr_lines = Arc::new(Mutex::new(Vec::<String>::new()));
+        let stdout_cloned = Arc::clone(&stdout_lines);
+        let stderr_cloned = Arc::clone(&stderr_lines);
+
+        let output = Cmd::builder("sh")
+            .args(["-c", "echo one; echo two >&2; sleep 0.1; printf 'three\\nfour'"])
+            .on_stdout_line(move |line| stdout_cloned.lock().unwrap().push(String::from_utf8_lossy(line).into_owned()))
+            .on_stderr_line(move |line| stderr_cloned.lock().unwrap().push(String::from_utf8_lossy(line).into_owned()))
+            .capture_stderr(false)
+            .build()
+            .output()
+            .unwrap();
+
+        assert_eq!(vec!["one", "three", "four"], *stdout_lines.lock().unwrap());
+        assert_eq!(vec!["two"], *stderr_lines.lock().unwrap());
+        assert_eq!("one\nthree\nfour", output.stdout.as_str().unwrap());
+        assert!(output.stderr.is_empty());
+    }
+
+    #[test]
+    fn test_chunk_callbacks() {
+        init_log!();
+        let received = Arc::new(Mutex::new(Vec::<u8>::new()));
+        let received_cloned = Arc::clone(&received);
+
+        let output = Cmd::builder("sh")
+            .args(["-c", "printf partial; sleep 0.2; echo ' line'"])
+            .on_stdout_chunk(move |chunk| received_cloned.lock().unwrap().extend(chunk))
+            .capture_stdout(false)
+            .build()
+            .output()
+            .unwrap();
+
+        assert_eq!(b"partial line\n".to_vec(), *received.lock().unwrap());
+        assert!(output.stdout.is_empty());
+    }
+
+    #[test]
+    fn test_stream() {
+        init_log!();
+        let events = Cmd::builder("sh")
+            .args(["-c", "echo one; sleep 0.1; echo two >&2; sleep 0.1; echo three; exit 3"])
+            .with_debug(true)
+            .build()
+            .stream()
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        assert_eq!(4, events.len());
+        assert_eq!(Event::Stdout(b"one".to_vec()), events[0]);
+        assert_eq!(Event::Stderr(b"two".to_vec()), events[1]);
+        assert_eq!(Event::Stdout(b"three".to_vec()), events[2]);
+        match events[3] {
+            Event::Exit(status) => assert_eq!(Some(3), status.code()),
+            _ => panic!("expected exit event"),
+        }
+
+        // the lines longer than the cap are split
+        let events = Cmd::builder("sh")
+            .args(["-c", "echo abcdefg"])
+            .max_line_bytes(3)
+            .build()
+            .stream()
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(4, events.len());
+        assert_eq!(Event::Stdout(b"abc".to_vec()), events[0]);
+        assert_eq!(Event::Stdout(b"def".to_vec()), events[1]);
+        assert_eq!(Event::Stdout(b"g".to_vec()), events[2]);
+    }
+
+    #[test]
+    fn test_stream_timeout() {
+        init_log!();
+        let now = Instant::now();
+        let mut stream = Cmd::builder("sh")
+            .args(["-c", "echo start; sleep 10"])
+            .with_timeout(Duration::from_millis(300))
+            .kill_tree(true)
+            .build()
+            .stream()
+            .unwrap();
+
+        assert_eq!(Event::Stdout(b"start".to_vec()), stream.next().unwrap().unwrap());
+        match stream.next().unwrap().unwrap() {
+            Event::Exit(status) => assert!(status.code().is_none()),
+            event => panic!("unexpected event {:?}", event),
+        }
+        assert!(stream.next().is_none());
+        assert!(now.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_spawn_handle() {
+        init_log!();
+        let mut handle = Cmd::builder("cat").stdin(Some(Stdio::piped())).build().spawn().unwrap();
+        assert!(handle.pid() > 0);
+        assert!(handle.try_wait().unwrap().is_none());
+        handle.stdin().unwrap().write_all(b"hello handle").unwrap();
+
+        let pid = handle.pid();
+        let output = handle.wait_with_output().unwrap();
+        assert!(output.success());
+        assert_eq!("hello handle", output.stdout.as_str().unwrap());
+        assert_eq!(pid, output.pid);
+        assert_eq!("cat", output.command_line);
+        assert_eq!(Termination::Exited, output.termination);
+
+        let output = Cmd::builder("sleep")
+            .arg("10")
+            .with_timeout(Duration::from_millis(100))
+            .build()
+            .spawn()
+            .unwrap()
+            .wait_with_output()
+            .unwrap();
+        assert!(output.timed_out());
+        assert!(output.elapsed < Duration::from_secs(5));
+
+        let mut handle = Cmd::builder("sleep").arg("10").build().spawn().unwrap();
+        assert!(handle.wait_timeout(Duration::from_millis(100)).unwrap().is_none());
+        handle.send_signal(signal_hook::consts::SIGTERM).unwrap();
+        let status = handle.wait().unwrap();
+        assert_eq!(Some(signal_hook::consts::SIGTERM), ExitStatusExt::signal(&status));
+    }
+
+    #[test]
+    fn test_spawn_handle_timeout() {
+        init_log!();
+        let now = Instant::now();
+        let mut handle = Cmd::builder("sleep")
+            .arg("10")
+            .with_timeout(Duration::from_millis(100))
+            .build()
+            .spawn()
+            .unwrap();
+        let status = handle.wait().unwrap();
+        assert!(!status.success());
+        assert!(now.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_spawn_handle_drop() {
+        init_log!();
+        let handle = Cmd::builder("sleep").arg("10").build().spawn().unwrap();
+        let pid = handle.pid() as libc::pid_t;
+        drop(handle);
+        // the child is killed and reaped in the background
+        sleep(Duration::from_millis(200));
+        assert_eq!(-1, unsafe { libc::kill(pid, 0) });
+
+        let handle = Cmd::builder("sleep").arg("10").kill_on_drop(false).build().spawn().unwrap();
+        let pid = handle.pid() as libc::pid_t;
+        drop(handle);
+        sleep(Duration::from_millis(200));
+        assert_eq!(0, unsafe { libc::kill(pid, 0) });
+        unsafe { libc::kill(pid, libc::SIGKILL) };
+
+        // the process group is left alone once the command has exited: `sleep` keeps the stdout open until it exits
+        let now = Instant::now();
+        let mut handle = Cmd::builder("sh").args(["-c", "sleep 1 &"]).kill_tree(true).build().spawn().unwrap();
+        let mut stdout = handle.take_stdout().unwrap();
+        assert!(handle.wait().unwrap().success());
+        drop(handle);
+        stdout.read_to_end(&mut Vec::new()).unwrap();
+        assert!(now.elapsed() >= Duration::from_secs(1), "elapsed: {:?}", now.elapsed());
+    }
+
+    #[test]
+    fn test_pipeline() {
+        init_log!();
+        let output = Pipeline::new()
+            .stage(Cmd::builder("printf").arg("b\\na\\nc\\n"))
+            .stage(Cmd::builder("grep").arg("-v").arg("c"))
+            .stage(Cmd::builder("sort").build())
+            .with_debug(true)
+            .output()
+            .unwrap();
+
+        assert!(output.success());
+        assert_eq!(3, output.statuses.len());
+        assert!(output.statuses.iter().all(|s| s.success()));
+        assert_eq!("a\nb\n", output.stdout.as_str().unwrap());
+
+        // grep fails to match anything, but sort succeeds
+        let pipeline = Pipeline::new()
+            .stage(Cmd::builder("echo").arg("hello"))
+            .stage(Cmd::builder("grep").arg("world"))
+            .stage(Cmd::builder("sort"));
+        let output = pipeline.output().unwrap();
+        assert!(output.success());
+        assert_eq!(Some(1), output.statuses[1].code());
+
+        let pipeline: Pipeline = vec![
+            Cmd::builder("echo").arg("hello"),
+            Cmd::builder("sh").args(["-c", "cat; echo failed >&2; exit 2"]),
+            Cmd::builder("sort"),
+        ]
+        .into_iter()
+        .collect();
+        let output = pipeline.pipefail(true).output().unwrap();
+        assert!(!output.success());
+        assert_eq!(Some(2), output.status.code());
+        assert_eq!("hello\n", output.stdout.as_str().unwrap());
+        assert_eq!("failed\n", output.stage_stderr[1].as_str().unwrap());
+    }
+
+    #[test]
+    fn test_pipeline_timeout() {
+        init_log!();
+        let now = Instant::now();
+        let output = Pipeline::new()
+            .stage(Cmd::builder("sleep").arg("10"))
+            .stage(Cmd::builder("cat"))
+            .stage(Cmd::builder("cat"))
+            .with_timeout(Duration::from_millis(200))
+            .pipefail(true)
+            .output()
+            .unwrap();
+
+        assert!(now.elapsed() < Duration::from_secs(5));
+        assert!(output.statuses.iter().all(|s| ExitStatusExt::signal(s) == Some(signal_hook::consts::SIGKILL)));
+        assert!(!output.success());
+
+        // a stage keeps its own timeout
+        let output = Pipeline::new()
+            .stage(Cmd::builder("sleep").arg("10").with_timeout(Duration::from_millis(200)))
+            .stage(Cmd::builder("cat"))
+            .output()
+            .unwrap();
+        assert!(output.success());
+        assert!(Output::from(output.clone()).success());
+        assert_eq!(Some(signal_hook::consts::SIGKILL), ExitStatusExt::signal(&output.statuses[0]));
+    }
 }
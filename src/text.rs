@@ -0,0 +1,57 @@
+use std::iter::Peekable;
+
+/// Removes the terminal escape sequences: the CSI sequences (colors, cursor movements, ...),
+/// the OSC sequences (window title, hyperlinks, ...), the sequences with intermediate bytes
+/// (character sets, ...) and the other two characters sequences.
+pub(crate) fn strip_ansi(text: &str) -> String {
+	let mut stripped = String::with_capacity(text.len());
+	let mut chars = text.chars().peekable();
+
+	while let Some(c) = chars.next() {
+		match c {
+			'\x1b' => match chars.next() {
+				Some('[') => skip_csi(&mut chars),
+				Some(']') => skip_osc(&mut chars),
+				Some('\x20'..='\x2f') => skip_intermediates(&mut chars),
+				// a two characters sequence, or a truncated one
+				_ => {}
+			},
+			'\u{9b}' => skip_csi(&mut chars),
+			_ => stripped.push(c),
+		}
+	}
+
+	stripped
+}
+
+/// Skips the parameters and the intermediate bytes of a CSI sequence, up to its final byte.
+fn skip_csi<I: Iterator<Item = char>>(chars: &mut I) {
+	for c in chars {
+		if ('\x40'..='\x7e').contains(&c) {
+			return;
+		}
+	}
+}
+
+/// Skips the rest of the intermediate bytes of an escape sequence, e.g. `ESC ( B`, and its final byte.
+fn skip_intermediates<I: Iterator<Item = char>>(chars: &mut I) {
+	for c in chars {
+		if !('\x20'..='\x2f').contains(&c) {
+			return;
+		}
+	}
+}
+
+/// Skips an OSC sequence, terminated by BEL or by ESC `\`.
+fn skip_osc<I: Iterator<Item = char>>(chars: &mut Peekable<I>) {
+	while let Some(c) = chars.next() {
+		match c {
+			'\x07' => return,
+			'\x1b' if chars.peek() == Some(&'\\') => {
+				chars.next();
+				return;
+			}
+			_ => {}
+		}
+	}
+}
@@ -0,0 +1,261 @@
+use std::io;
+use std::process::{Child, ExitStatus};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crossbeam::channel::Receiver;
+use crossbeam_channel::{after, bounded, never, select, Sender};
+use tracing::{warn, Span};
+
+use crate::{TermPolicy, Termination};
+
+/// Owns a spawned child and reaps it from a dedicated thread, which blocks in `waitid(2)`
+/// until the child exits instead of polling it.
+///
+/// The child is first waited with `WNOWAIT`, so that it stays a zombie, and it's only reaped
+/// while holding the status lock. Signals are sent while holding the same lock, which
+/// guarantees that the pid is never signalled after it has been recycled.
+///
+/// With `tree`, the process group is still signalled after the child has been reaped, since its
+/// other members may keep the output pipes open: the pgid can't be recycled while the group has members.
+#[derive(Debug)]
+pub(crate) struct ChildWaiter {
+	pid: u32,
+	tree: bool,
+	state: Arc<(Mutex<Option<io::Result<ExitStatus>>>, Condvar)>,
+	exited: Receiver<()>,
+	// with `tree`, disconnected by `release` once the output of the group has been read
+	released: Receiver<()>,
+	release: Mutex<Option<Sender<()>>>,
+}
+
+impl ChildWaiter {
+	/// When `tree` is true signals are sent to the whole process group of the child.
+	/// The child is killed and reaped if it can't be waited for.
+	pub(crate) fn new(mut child: Child, tree: bool) -> io::Result<Self> {
+		let pid = child.id();
+		let state = Arc::new((Mutex::new(None), Condvar::new()));
+		let state_cloned = Arc::clone(&state);
+
+		// the sender is never used: dropping it disconnects every clone of the receiver
+		let (exit_sender, exited) = bounded::<()>(0);
+		let (release_sender, released) = bounded::<()>(0);
+		let release = if tree { Some(release_sender) } else { None };
+
+		// the child is handed over once the thread is running, so that it's still ours if the thread can't start
+		let (child_sender, child_receiver) = bounded::<Child>(1);
+		let spawned = std::thread::Builder::new().name("cmd_exit".to_string()).spawn(move || {
+			let _exit_sender: Sender<()> = exit_sender;
+			let Ok(mut child) = child_receiver.recv() else {
+				return;
+			};
+			wait_no_reap(pid);
+
+			let (lock, condvar) = &*state_cloned;
+			let mut status = lock.lock().unwrap();
+			*status = Some(child.wait());
+			condvar.notify_all();
+		});
+
+		if let Err(err) = spawned {
+			let _ = child.kill();
+			let _ = child.wait();
+			return Err(err);
+		}
+		let _ = child_sender.send(child);
+
+		Ok(ChildWaiter {
+			pid,
+			tree,
+			state,
+			exited,
+			released,
+			release: Mutex::new(release),
+		})
+	}
+
+	pub(crate) fn id(&self) -> u32 {
+		self.pid
+	}
+
+	/// Returns the receivers which are disconnected when there is nothing left to supervise: the first one
+	/// as soon as the child has been reaped, the second one, with `tree`, once the output has been read.
+	pub(crate) fn finished(&self) -> [Receiver<()>; 2] {
+		[self.exited.clone(), self.released.clone()]
+	}
+
+	/// Tells the supervisor that the output of the process group has been read, or won't be.
+	pub(crate) fn release(&self) {
+		self.release.lock().unwrap().take();
+	}
+
+	/// Sends `signal` to the child (or to its process group). Does nothing if the child has already been reaped,
+	/// or with `tree` if the group has no members left.
+	pub(crate) fn signal(&self, signal: i32) -> io::Result<()> {
+		let (lock, _) = &*self.state;
+		let status = lock.lock().unwrap();
+		if status.is_some() && !self.tree {
+			return Ok(());
+		}
+
+		let pid = self.pid as libc::pid_t;
+		let pid = if self.tree { -pid } else { pid };
+		if unsafe { libc::kill(pid, signal) } == 0 {
+			return Ok(());
+		}
+
+		match io::Error::last_os_error() {
+			err if status.is_some() && err.raw_os_error() == Some(libc::ESRCH) => Ok(()),
+			err => Err(err),
+		}
+	}
+
+	pub(crate) fn try_wait(&self) -> io::Result<Option<ExitStatus>> {
+		let (lock, _) = &*self.state;
+		let status = lock.lock().unwrap();
+		status.as_ref().map(copy_status).transpose()
+	}
+
+	pub(crate) fn wait(&self) -> io::Result<ExitStatus> {
+		let (lock, condvar) = &*self.state;
+		let status = condvar.wait_while(lock.lock().unwrap(), |s| s.is_none()).unwrap();
+		copy_status(status.as_ref().unwrap())
+	}
+
+	pub(crate) fn wait_timeout(&self, timeout: Duration) -> io::Result<Option<ExitStatus>> {
+		let (lock, condvar) = &*self.state;
+		let (status, _) = condvar
+			.wait_timeout_while(lock.lock().unwrap(), timeout, |s| s.is_none())
+			.unwrap();
+		status.as_ref().map(copy_status).transpose()
+	}
+}
+
+fn copy_status(status: &io::Result<ExitStatus>) -> io::Result<ExitStatus> {
+	match status {
+		Ok(status) => Ok(*status),
+		Err(err) => Err(io::Error::new(err.kind(), err.to_string())),
+	}
+}
+
+/// Blocks until the child has exited, leaving it in a waitable state.
+fn wait_no_reap(pid: u32) {
+	loop {
+		let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+		let result = unsafe {
+			libc::waitid(
+				libc::P_PID,
+				pid as libc::id_t,
+				&mut info,
+				libc::WEXITED | libc::WNOWAIT,
+			)
+		};
+		if result == 0 || io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+			break;
+		}
+	}
+}
+
+/// Enforces the timeout and the cancel signal over a set of children, terminating all of them
+/// according to the [`TermPolicy`]. The supervisor thread sleeps until one of the events occurs.
+pub(crate) struct Supervisor {
+	pub(crate) cancel: Option<Receiver<()>>,
+	pub(crate) timeout: Option<Duration>,
+	pub(crate) termination: TermPolicy,
+	pub(crate) debug: bool,
+	/// The span of the execution, entered by the supervisor thread.
+	pub(crate) span: Span,
+	/// Set when the supervisor terminates the children.
+	pub(crate) cause: Arc<OnceLock<Termination>>,
+}
+
+/// Kills and reaps the children, when the command or the pipeline they belong to fails to start.
+fn kill_all(waiters: &[Arc<ChildWaiter>]) {
+	for waiter in waiters {
+		let _ = waiter.signal(signal_hook::consts::SIGKILL);
+		let _ = waiter.wait();
+		waiter.release();
+	}
+}
+
+/// Returns `result`, after killing the children with [`kill_all`] if it's an error.
+pub(crate) fn or_kill_all<T, E>(result: Result<T, E>, waiters: &[Arc<ChildWaiter>]) -> Result<T, E> {
+	if result.is_err() {
+		kill_all(waiters);
+	}
+	result
+}
+
+impl Supervisor {
+	/// Returns `None` when there is nothing to supervise.
+	pub(crate) fn spawn(self, waiters: Vec<Arc<ChildWaiter>>) -> io::Result<Option<JoinHandle<()>>> {
+		if self.cancel.is_none() && self.timeout.is_none() {
+			return Ok(None);
+		}
+
+		let handle = std::thread::Builder::new()
+			.name("cmd_wait".to_string())
+			.spawn(move || self.run(waiters))?;
+		Ok(Some(handle))
+	}
+
+	fn run(self, waiters: Vec<Arc<ChildWaiter>>) {
+		let span = self.span.clone();
+		let _enter = span.enter();
+		let termination = self.termination;
+		let signal_all = |signal: i32| {
+			// the last stages of a pipeline first, so they can't exit normally when their stdin is closed
+			for waiter in waiters.iter().rev() {
+				let _ = waiter.signal(signal);
+			}
+		};
+
+		let mut cancel = self.cancel.unwrap_or_else(never);
+		let mut timeout = self.timeout.map(after).unwrap_or_else(never);
+		let mut escalate = never();
+		let mut terminated = false;
+
+		for finished in waiters.iter().flat_map(|waiter| waiter.finished()) {
+			loop {
+				select! {
+					recv(finished) -> _ => break,
+
+					recv(cancel) -> msg => {
+						cancel = never();
+						if msg.is_ok() && !terminated {
+							if self.debug {
+								warn!("ctrl+c received");
+							}
+							let _ = self.cause.set(Termination::Cancelled);
+							signal_all(termination.signal);
+							escalate = termination.escalation();
+							terminated = true;
+						}
+					}
+
+					recv(timeout) -> _ => {
+						timeout = never();
+						if !terminated {
+							if self.debug {
+								warn!("command timeout! terminating the process...");
+							}
+							let _ = self.cause.set(Termination::TimedOut);
+							signal_all(termination.signal);
+							escalate = termination.escalation();
+							terminated = true;
+						}
+					}
+
+					recv(escalate) -> _ => {
+						escalate = never();
+						if self.debug {
+							warn!("grace period expired, sending signal {}...", termination.then);
+						}
+						signal_all(termination.then);
+					}
+				}
+			}
+		}
+	}
+}